@@ -0,0 +1,82 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::BackupDir;
+
+// An entry in `backup_dirs`, e.g. `{ kind = "home", path = "Documents" }`
+#[derive(Deserialize)]
+#[serde(tag = "kind", content = "path", rename_all = "lowercase")]
+enum ConfigBackupDir {
+    Home(String),
+    Root(String),
+    Xdg(String),
+}
+
+/// Settings that can live in config.toml instead of being compiled in. Anything left out falls
+/// back to the built-in default.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    // Replaces MAC_BACKUP_DIRS, WINDOWS_BACKUP_DIRS or LINUX_BACKUP_DIRS, whichever OS this is
+    backup_dirs: Option<Vec<ConfigBackupDir>>,
+    // Replaces EXCLUDE_PATTERNS
+    excludes: Option<Vec<String>>,
+}
+
+static CONFIG: OnceLock<ConfigFile> = OnceLock::new();
+
+fn default_path() -> anyhow::Result<PathBuf> {
+    Ok(crate::home_dir()?.join(".config/backuper/config.toml"))
+}
+
+/// Reads the config file, from `path` if given or the default location otherwise. Only a missing
+/// file at the default location is fine.
+pub fn load(path: Option<&Path>) -> anyhow::Result<()> {
+    let (path, explicit) = match path {
+        Some(path) => (path.to_path_buf(), true),
+        None => (default_path()?, false),
+    };
+    let config = match fs::read_to_string(&path) {
+        Ok(contents) => {
+            log::info!("Using config file {}", path.display());
+            toml::from_str(&contents)
+                .with_context(|| format!("Malformed config file {}", path.display()))?
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => {
+            log::info!(
+                "No config file at {}, using the built-in defaults",
+                path.display()
+            );
+            ConfigFile::default()
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read config file {}", path.display()))
+        }
+    };
+    let _ = CONFIG.set(config);
+    Ok(())
+}
+
+pub fn backup_dirs() -> Option<Vec<BackupDir<'static>>> {
+    let dirs = CONFIG.get()?.backup_dirs.as_ref()?;
+    Some(
+        dirs.iter()
+            .map(|dir| match dir {
+                ConfigBackupDir::Home(path) => BackupDir::Home(path),
+                ConfigBackupDir::Root(path) => BackupDir::Root(path),
+                ConfigBackupDir::Xdg(path) => BackupDir::Xdg(path),
+            })
+            .collect(),
+    )
+}
+
+pub fn excludes() -> Option<Vec<&'static str>> {
+    let excludes = CONFIG.get()?.excludes.as_ref()?;
+    Some(excludes.iter().map(String::as_str).collect())
+}
@@ -0,0 +1,117 @@
+// A local copy of restic's exclude pattern matching (internal/filter in restic), close enough to
+// explain which pattern excludes a path
+
+/// Splits a path into components, with "/" as the first one for absolute paths like restic
+fn split_path(path: &str) -> Vec<String> {
+    let path = path.replace('\\', "/");
+    let mut parts: Vec<String> = Vec::new();
+    if path.starts_with('/') {
+        parts.push("/".into());
+    }
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                if parts.last().is_some_and(|p| p != "/" && p != "..") {
+                    parts.pop();
+                } else {
+                    parts.push("..".into());
+                }
+            }
+            _ => parts.push(part.into()),
+        }
+    }
+    parts
+}
+
+/// Go's filepath.Match for a single path component: `*`, `?`, `[...]` classes and `\` escapes
+fn match_component(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| match_component(rest, &name[i..])),
+        Some(('?', rest)) => !name.is_empty() && match_component(rest, &name[1..]),
+        Some(('[', rest)) => {
+            let Some((c, name_rest)) = name.split_first() else {
+                return false;
+            };
+            let (negated, rest) = match rest.split_first() {
+                Some(('^', rest)) => (true, rest),
+                _ => (false, rest),
+            };
+            let Some(end) = rest.iter().position(|&ch| ch == ']') else {
+                return false;
+            };
+            let class = &rest[..end];
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                let lo = class[i];
+                if i + 2 < class.len() && class[i + 1] == '-' {
+                    matched |= lo <= *c && *c <= class[i + 2];
+                    i += 3;
+                } else {
+                    matched |= lo == *c;
+                    i += 1;
+                }
+            }
+            matched != negated && match_component(&rest[end + 1..], name_rest)
+        }
+        Some(('\\', rest)) if !rest.is_empty() => {
+            name.first() == Some(&rest[0]) && match_component(&rest[1..], &name[1..])
+        }
+        Some((ch, rest)) => name.first() == Some(ch) && match_component(rest, &name[1..]),
+    }
+}
+
+fn match_parts(pattern: &[String], path: &[String]) -> bool {
+    // "**" matches any number of components, so try it as zero, one, two... "*"s
+    if let Some(pos) = pattern.iter().position(|p| p == "**") {
+        let max_stars = (path.len() + 1).saturating_sub(pattern.len());
+        return (0..=max_stars).any(|stars| {
+            let mut expanded = pattern[..pos].to_vec();
+            expanded.extend(std::iter::repeat_n("*".to_owned(), stars));
+            expanded.extend_from_slice(&pattern[pos + 1..]);
+            match_parts(&expanded, path)
+        });
+    }
+    if pattern.is_empty() || pattern.len() > path.len() {
+        return false;
+    }
+
+    // Patterns match anywhere in the path unless they're absolute
+    let max_offset = path.len() - pattern.len();
+    let (min_offset, max_offset) = if pattern[0] == "/" {
+        (0, 0)
+    } else if path[0] == "/" {
+        (1, max_offset)
+    } else {
+        (0, max_offset)
+    };
+    (min_offset..=max_offset).rev().any(|offset| {
+        pattern.iter().zip(&path[offset..]).all(|(p, name)| {
+            let p: Vec<char> = p.chars().collect();
+            let name: Vec<char> = name.chars().collect();
+            match_component(&p, &name)
+        })
+    })
+}
+
+/// Whether a single exclude pattern (without a leading `!`) matches the path
+pub fn matches(pattern: &str, path: &str) -> bool {
+    match_parts(&split_path(pattern), &split_path(path))
+}
+
+/// The pattern that decides whether the path is excluded, and whether it excludes it. Like
+/// restic, the last matching pattern wins, and `!pattern` re-includes what earlier ones excluded.
+pub fn deciding_pattern<'a>(patterns: &'a [String], path: &str) -> Option<(&'a str, bool)> {
+    patterns
+        .iter()
+        .rev()
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty() && !p.starts_with('#'))
+        .filter_map(|p| match p.strip_prefix('!') {
+            Some(negated) => matches(negated, path).then_some((p, false)),
+            None => matches(p, path).then_some((p, true)),
+        })
+        .next()
+}
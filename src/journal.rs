@@ -0,0 +1,136 @@
+// Logging straight to journald with its native protocol, so journalctl can filter on fields like
+// BACKUPER_TASK and BACKUPER_STATUS. Only used when systemd connected our stderr to the journal.
+
+#[cfg(unix)]
+use std::{
+    io,
+    os::unix::net::UnixDatagram,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+#[cfg(unix)]
+static JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+// Set once init_logger has taken over logging
+#[cfg(unix)]
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Whether stderr goes to the journal. systemd sets JOURNAL_STREAM to the device and inode of
+/// the stream, which we compare against stderr in case it was redirected since.
+#[cfg(unix)]
+pub fn is_connected() -> bool {
+    let Ok(stream) = std::env::var("JOURNAL_STREAM") else {
+        return false;
+    };
+    let Some((dev, ino)) = stream.split_once(':') else {
+        return false;
+    };
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(libc::STDERR_FILENO, &mut stat) } != 0 {
+        return false;
+    }
+    dev.parse() == Ok(stat.st_dev as u64) && ino.parse() == Ok(stat.st_ino as u64)
+}
+
+#[cfg(not(unix))]
+pub fn is_connected() -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn send(fields: &[(&str, &str)]) -> io::Result<()> {
+    let mut buf = Vec::new();
+    for (key, value) in fields {
+        buf.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            // Multi-line values need the length-prefixed form
+            buf.push(b'\n');
+            buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            buf.push(b'=');
+        }
+        buf.extend_from_slice(value.as_bytes());
+        buf.push(b'\n');
+    }
+    UnixDatagram::unbound()?.send_to(&buf, JOURNAL_SOCKET)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn priority(level: log::Level) -> &'static str {
+    match level {
+        log::Level::Error => "3",
+        log::Level::Warn => "4",
+        log::Level::Info => "6",
+        log::Level::Debug | log::Level::Trace => "7",
+    }
+}
+
+#[cfg(unix)]
+struct JournalLogger;
+
+#[cfg(unix)]
+impl log::Log for JournalLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = record.args().to_string();
+        let _ = send(&[
+            ("MESSAGE", &message),
+            ("PRIORITY", priority(record.level())),
+            ("SYSLOG_IDENTIFIER", "backuper"),
+        ]);
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(unix)]
+pub fn init_logger() {
+    log::set_max_level(log::LevelFilter::Info);
+    if log::set_logger(&JournalLogger).is_ok() {
+        ACTIVE.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn init_logger() {}
+
+#[cfg(unix)]
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+#[cfg(not(unix))]
+pub fn is_active() -> bool {
+    false
+}
+
+/// Logs a task finishing, with its name, duration and status as separate fields
+#[cfg(unix)]
+pub fn log_task(level: log::Level, message: &str, task: &str, duration_ms: u128, status: &str) {
+    let duration_ms = duration_ms.to_string();
+    let _ = send(&[
+        ("MESSAGE", message),
+        ("PRIORITY", priority(level)),
+        ("SYSLOG_IDENTIFIER", "backuper"),
+        ("BACKUPER_TASK", task),
+        ("BACKUPER_DURATION_MS", &duration_ms),
+        ("BACKUPER_STATUS", status),
+    ]);
+}
+
+#[cfg(not(unix))]
+pub fn log_task(
+    _level: log::Level,
+    _message: &str,
+    _task: &str,
+    _duration_ms: u128,
+    _status: &str,
+) {
+}
@@ -1,12 +1,28 @@
+mod config_file;
+mod exclude_match;
+mod journal;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "notifiers")]
+mod notify;
+mod progress;
+mod restic_error;
+
 use anyhow::{anyhow, Context};
-use lettre::{
-    message::header::ContentType, transport::smtp::authentication::Credentials, Message,
-    SmtpTransport, Transport,
-};
+use chrono::Datelike;
+use serde::{Deserialize, Serialize};
 use std::{
-    env,
-    io::Write,
-    process::{Command, Stdio},
+    cell::Cell,
+    collections::{BTreeMap, BTreeSet},
+    env, fmt, fs,
+    io::{self, BufRead, BufReader, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    process::{self, Child, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
+    thread,
     time::{self, Duration},
 };
 
@@ -14,9 +30,14 @@ use std::{
 // Start backup config
 //
 
+// These are the defaults when config.toml doesn't set backup_dirs or excludes.
+// Despite the name, these can also point at single files, e.g. BackupDir::Home(".ssh/config")
+#[derive(Clone, Copy)]
 enum BackupDir<'a> {
     Home(&'a str),
     Root(&'a str),
+    // Under $XDG_CONFIG_HOME, or ~/.config if that's unset
+    Xdg(&'a str),
 }
 
 static MAC_BACKUP_DIRS: &[BackupDir] = &[
@@ -28,6 +49,16 @@ static MAC_BACKUP_DIRS: &[BackupDir] = &[
     BackupDir::Home("Library/Application Support/Anki2"),
 ];
 
+static LINUX_BACKUP_DIRS: &[BackupDir] = &[
+    BackupDir::Home("Documents"),
+    BackupDir::Home("Pictures"),
+    BackupDir::Home("Music"),
+    BackupDir::Home("Videos"),
+    BackupDir::Home("Dropbox"),
+    BackupDir::Home(".local/share/Anki2"),
+    // BackupDir::Xdg("nvim"),
+];
+
 static WINDOWS_BACKUP_DIRS: &[BackupDir] = &[
     BackupDir::Home("Documents"),
     // BackupDir::Home("Pictures"),
@@ -57,15 +88,257 @@ static EXCLUDE_PATTERNS: &[&str] = &[
     "build*/**",
 ];
 
+// Extra patterns for repos that want to exclude more, e.g. a small cloud repo. Each tier also
+// gets the patterns of the tiers below it, and every tier gets EXCLUDE_PATTERNS.
+static TIER_EXCLUDE_PATTERNS: &[(ExcludeTier, &[&str])] = &[
+    (ExcludeTier::Standard, &[]),
+    (
+        ExcludeTier::Aggressive,
+        &[
+            // "*.iso",
+            // "Library/Application Support/Steam/**",
+        ],
+    ),
+];
+
+// Merged in order after EXCLUDE_PATTERNS, e.g. a shared base file then a machine-specific one.
+// Missing files are skipped with a warning.
+static EXCLUDE_FILES: &[BackupDir] = &[
+    // BackupDir::Home(".config/backuper/excludes"),
+];
+
+// Keep backuper's own working dirs (restic's cache and our temp files) out of the backups, in
+// case they live under a backed up dir. Set to false to back them up like anything else.
+static EXCLUDE_OWN_DIRS: bool = true;
+
+// Named sets of extra env vars for restic, which repos pick with `env_profiles`, e.g.
+// ("work", &[("RESTIC_CACHE_DIR", "/Volumes/Work/restic-cache")]).
+// Profiles override the env backuper runs with, and a repo's own settings (repository, password,
+// AWS keys) can't be overridden by profiles. Two profiles on one repo can't set the same var.
+static ENV_PROFILES: &[(&str, &[(&str, &str)])] = &[];
+
+// What a backup run does, in order
+enum TaskSpec<'a> {
+    // choco/apt/brew on Windows, brew on macOS, apt or pacman (and brew if it's there) on Linux
+    Upgrade,
+    // Back up to the repo with this name. Consecutive backups run as one batch that respects each
+    // repo's order and concurrency group.
+    Backup(&'a str),
+    // Run any command as its own task
+    #[allow(dead_code)] // Not in the default task lists
+    Hook {
+        name: &'a str,
+        cmd: &'a [&'a str],
+    },
+}
+
+static WINDOWS_TASKS: &[TaskSpec] = &[
+    TaskSpec::Upgrade,
+    TaskSpec::Backup("Local"),
+    TaskSpec::Backup("Cloud"),
+];
+
+static MAC_TASKS: &[TaskSpec] = &[
+    TaskSpec::Upgrade,
+    TaskSpec::Backup("Cloud"),
+    // TaskSpec::Hook { name: "Notify Phone", cmd: &["ntfy", "send", "Backup done"] },
+];
+
+static LINUX_TASKS: &[TaskSpec] = &[TaskSpec::Upgrade, TaskSpec::Backup("Cloud")];
+
+// On macOS, also exclude whatever Time Machine is set to skip among the backup dirs and their
+// immediate contents, as reported by `tmutil isexcluded`
+static IMPORT_TIME_MACHINE_EXCLUDES: bool = false;
+
+// Tasks not listed here are allowed to run for as long as they need
+static TASK_TIMEOUTS: &[(&str, Duration)] = &[
+    ("Windows Upgrades", Duration::from_secs(2 * 60 * 60)),
+    ("macOS Upgrades", Duration::from_secs(60 * 60)),
+    ("Linux Upgrades", Duration::from_secs(60 * 60)),
+];
+// Each `brew upgrade` is killed after this, so one stuck formula doesn't use up the whole task
+static BREW_UPGRADE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+// Run after every backup with the JSON RunSummary on stdin, e.g. &["python3", "report.py"]
+static REPORT_COMMAND: &[&str] = &[];
+
+// If set, warn before backing up if the local clock is off from this server's Date header by
+// more than MAX_CLOCK_SKEW, since misdated snapshots confuse retention
+static CLOCK_CHECK_URL: Option<&str> = None;
+static MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+// For the cost subcommand, in dollars. Roughly Backblaze B2's price.
+static DEFAULT_COST_PER_GB_MONTH: f64 = 0.006;
+
+// With --check-quota, refuse to back up to a repo with a storage cap when its estimated size
+// leaves less than this under the cap
+static MIN_FREE_QUOTA_GB: f64 = 1.0;
+
+// Total upload rate in KiB/s for all restic processes at once, split evenly between the repos
+// backed up concurrently (and a repo's parallel_backups). None means no overall limit.
+static UPLOAD_BUDGET_KIB: Option<u32> = None;
+
+// Resolving to more backup paths than this probably means a config mistake
+static MAX_BACKUP_PATHS: usize = 1000;
+
+// Unix permissions for every file backuper creates, since they can contain paths and config.
+// The metrics and heartbeat files get SHARED_FILE_MODE instead, since a collector or watchdog
+// running as another user reads them.
+static CREATED_FILE_MODE: u32 = 0o600;
+static SHARED_FILE_MODE: u32 = 0o644;
+
+// restic refreshes its locks every few minutes, so older ones are likely left over from a
+// process that died
+static STALE_LOCK_AGE: Duration = Duration::from_secs(30 * 60);
+// Whether a backup that fails on a lock older than STALE_LOCK_AGE runs `restic unlock` and tries
+// once more
+static UNLOCK_STALE_LOCKS: bool = true;
+
+// How often the local repos get a `restic check`, reading back this much of their data. The
+// cloud repo is only checked if BACKUPER_CHECK_INTERVAL_DAYS is set.
+static LOCAL_CHECK_INTERVAL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+static LOCAL_CHECK_READ_DATA_PERCENT: f64 = 10.0;
+
+// Temp files from runs that died are removed once they're this old
+static STALE_TEMP_FILE_AGE: Duration = Duration::from_secs(2 * 24 * 60 * 60);
+
+// If set, task results and duration histograms are written here in OpenMetrics text format after
+// every backup, e.g. for node_exporter's textfile collector. Needs the metrics feature.
+#[cfg(feature = "metrics")]
+static METRICS_FILE: Option<&str> = None;
+// Upper bounds of the duration histogram buckets, in seconds. Changing these restarts the counts.
+#[cfg(feature = "metrics")]
+static DURATION_BUCKETS_SECS: &[f64] = &[60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 14400.0];
+
+// If set, the current time is written here every HEARTBEAT_INTERVAL while a task is running, so
+// a watchdog can tell a hung run from a long one. Nothing is written between tasks.
+static HEARTBEAT_FILE: Option<&str> = None;
+static HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+// How many of the biggest directories to list with --file-counts
+static FILE_COUNT_TOP_N: usize = 10;
+
 //
 // End backup config
 //
 
+#[derive(Default)]
 struct ResticConfig {
+    name: String,
     restic_repository: String,
     restic_password: String,
+    // Passed to restic as RESTIC_PASSWORD_COMMAND instead of restic_password when set. Under WSL
+    // this runs on the Linux side.
+    password_command: Option<String>,
     aws_access_key_id: Option<String>,
     aws_secret_access_key: Option<String>,
+    // For filesystems where inodes/ctimes change on every mount, e.g. some network mounts
+    ignore_inode: bool,
+    ignore_ctime: bool,
+    // Wait this long (e.g. "30m") for a lock held by another machine instead of failing
+    retry_lock: Option<String>,
+    // Repos with a lower order are backed up first, ties run in declaration order. The local repos
+    // are 0, so a negative order puts the cloud repo first.
+    order: i32,
+    // Repos in the same group are backed up one at a time, different groups run in parallel.
+    // Everything is in the default "" group unless configured otherwise.
+    concurrency_group: String,
+    // Only back up on these days, e.g. weekends for an expensive cloud repo. Empty means every day.
+    weekdays: Vec<chrono::Weekday>,
+    // Storage price in dollars for the cost subcommand, DEFAULT_COST_PER_GB_MONTH if unset
+    cost_per_gb_month: Option<f64>,
+    // A cap of our own for --check-quota, e.g. set to match a B2 storage cap, which B2 doesn't
+    // expose in its API. It's compared against the repo's raw size from `restic stats`, which
+    // only estimates what the bucket holds.
+    storage_cap_gb: Option<f64>,
+    profile: Option<PerfProfile>,
+    // Back up the dirs with up to this many restic processes at once, each making its own
+    // snapshot, for more throughput than one restic manages. 0 or 1 means a single snapshot.
+    parallel_backups: usize,
+    // Individual restic tuning flags, overriding the profile's
+    tuning: Tuning,
+    // restic's --limit-upload in KiB/s, lowered further to stay within UPLOAD_BUDGET_KIB
+    limit_upload_kib: Option<u32>,
+    // On a local disk or the LAN, so UPLOAD_BUDGET_KIB doesn't apply
+    local: bool,
+    // Names from ENV_PROFILES to add to restic's env
+    env_profiles: Vec<String>,
+    exclude_tier: ExcludeTier,
+    keep: KeepPolicy,
+    // Run `restic check` after a successful backup and prune if the last check was longer ago than
+    // this. None means never.
+    check_interval: Option<Duration>,
+    // Also read back this percentage of the data when checking, restic's --read-data-subset.
+    // Reading it all costs egress on cloud repos.
+    check_read_data_percent: Option<f64>,
+    // Run `restic init` before backing up if the repo doesn't exist yet. Off by default so a typo
+    // in the repo URL fails instead of quietly starting a new repo.
+    init_if_missing: bool,
+}
+
+/// How many snapshots `restic forget` keeps, per group of host and paths
+struct KeepPolicy {
+    daily: u32,
+    weekly: u32,
+    monthly: u32,
+}
+
+impl Default for KeepPolicy {
+    fn default() -> Self {
+        Self {
+            daily: 7,
+            weekly: 4,
+            monthly: 12,
+        }
+    }
+}
+
+/// How much a repo excludes, see TIER_EXCLUDE_PATTERNS
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+enum ExcludeTier {
+    Minimal,
+    #[default]
+    Standard,
+    Aggressive,
+}
+
+impl std::str::FromStr for ExcludeTier {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "minimal" => Ok(ExcludeTier::Minimal),
+            "standard" => Ok(ExcludeTier::Standard),
+            "aggressive" => Ok(ExcludeTier::Aggressive),
+            _ => Err(anyhow!("Unknown exclude tier: {s}")),
+        }
+    }
+}
+
+/// Finds the home dir, which also has to work under cron/systemd where HOME may be unset.
+/// BACKUPER_HOME_DIR overrides it explicitly.
+fn home_dir() -> anyhow::Result<&'static Path> {
+    static HOME_DIR: OnceLock<PathBuf> = OnceLock::new();
+    if let Some(home) = HOME_DIR.get() {
+        return Ok(home);
+    }
+
+    let (home, method) = if let Ok(home) = env::var("BACKUPER_HOME_DIR") {
+        (PathBuf::from(home), "BACKUPER_HOME_DIR")
+    } else {
+        let method = if env::var_os("HOME").is_some() {
+            "the HOME env var"
+        } else {
+            // homedir falls back to the passwd database on Unix and the profile dir on Windows
+            "the user database"
+        };
+        let home = homedir::my_home()?.ok_or(anyhow!(
+            "Failed to get home dir from {method}, set BACKUPER_HOME_DIR"
+        ))?;
+        (home, method)
+    };
+    log::info!("Resolved home dir {} from {method}", home.display());
+    Ok(HOME_DIR.get_or_init(|| home))
 }
 
 fn backup_dirs_to_strings(backup_dirs: &[BackupDir]) -> anyhow::Result<Vec<String>> {
@@ -73,15 +346,224 @@ fn backup_dirs_to_strings(backup_dirs: &[BackupDir]) -> anyhow::Result<Vec<Strin
         .iter()
         .map(|d| match d {
             BackupDir::Home(path_str) => {
-                let mut path = homedir::my_home()?.ok_or(anyhow!("Failed to get home dir"))?;
+                let mut path = home_dir()?.to_path_buf();
                 path.push(path_str);
                 Ok(path.to_string_lossy().to_string())
             }
             BackupDir::Root(path_str) => Ok(path_str.to_string()),
+            BackupDir::Xdg(path_str) => {
+                let mut path = match env::var_os("XDG_CONFIG_HOME") {
+                    Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+                    _ => home_dir()?.join(".config"),
+                };
+                path.push(path_str);
+                Ok(path.to_string_lossy().to_string())
+            }
         })
         .collect()
 }
 
+/// What to do with a backup dir that's a symlink to something missing, e.g. an unmounted volume.
+/// Set with BACKUPER_BROKEN_SYMLINKS.
+#[derive(Default)]
+enum BrokenSymlinks {
+    // Leave it out with a warning
+    #[default]
+    Skip,
+    // Refuse to back up anything
+    Fail,
+    // Back up the link itself
+    Include,
+}
+
+impl std::str::FromStr for BrokenSymlinks {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "skip" => Ok(BrokenSymlinks::Skip),
+            "fail" => Ok(BrokenSymlinks::Fail),
+            "include" => Ok(BrokenSymlinks::Include),
+            _ => Err(anyhow!("Unknown broken symlink handling: {s}")),
+        }
+    }
+}
+
+/// The backup dirs from config.toml, or the built-in ones for this OS
+fn configured_backup_dirs(args: &Args) -> Vec<BackupDir<'static>> {
+    config_file::backup_dirs().unwrap_or_else(|| match args.os {
+        Os::Windows => WINDOWS_BACKUP_DIRS.to_vec(),
+        Os::Mac => MAC_BACKUP_DIRS.to_vec(),
+        Os::Linux => LINUX_BACKUP_DIRS.to_vec(),
+    })
+}
+
+/// Resolves the backup dirs and drops the ones that don't exist on this machine
+fn resolve_backup_dirs(backup_dirs: &[BackupDir], args: &Args) -> anyhow::Result<Vec<String>> {
+    let broken_symlinks: BrokenSymlinks = env::var("BACKUPER_BROKEN_SYMLINKS")
+        .ok()
+        .map(|b| b.parse())
+        .transpose()?
+        .unwrap_or_default();
+    let mut resolved = Vec::new();
+    for dir in backup_dirs_to_strings(backup_dirs)? {
+        let path = Path::new(&dir);
+        if path.exists() {
+            resolved.push(dir);
+            continue;
+        }
+        // exists() follows symlinks, so a dangling one still has metadata of its own
+        if !path.is_symlink() {
+            log::warn!("Backup dir does not exist, skipping: {dir}");
+            continue;
+        }
+        let target = fs::read_link(path)?;
+        match broken_symlinks {
+            BrokenSymlinks::Skip => log::warn!(
+                "Backup dir is a symlink to missing {}, skipping: {dir}",
+                target.display()
+            ),
+            BrokenSymlinks::Fail => {
+                return Err(anyhow!(
+                    "Backup dir is a symlink to missing {}: {dir}",
+                    target.display()
+                ))
+            }
+            BrokenSymlinks::Include => {
+                log::warn!(
+                    "Backup dir is a symlink to missing {}, backing up the link: {dir}",
+                    target.display()
+                );
+                resolved.push(dir);
+            }
+        }
+    }
+
+    log::info!("Resolved {} backup dirs", resolved.len());
+    // An empty snapshot could later let retention forget the real ones
+    if resolved.is_empty() && !args.allow_empty {
+        return Err(anyhow!(
+            "No backup dirs exist, refusing to back up nothing (pass --allow-empty to override)"
+        ));
+    }
+    check_path_limit(&resolved, args)?;
+    Ok(resolved)
+}
+
+/// Warns, or fails with --fail-on-path-limit, if there are more than MAX_BACKUP_PATHS paths
+fn check_path_limit(paths: &[String], args: &Args) -> anyhow::Result<()> {
+    if paths.len() > MAX_BACKUP_PATHS {
+        let msg = format!(
+            "Resolved {} backup paths, more than the limit of {MAX_BACKUP_PATHS}",
+            paths.len()
+        );
+        if args.fail_on_path_limit {
+            return Err(anyhow!(msg));
+        }
+        log::warn!("{msg}");
+    }
+    Ok(())
+}
+
+fn count_files(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(t) if t.is_dir() => count_files(&entry.path()),
+            Ok(_) => 1,
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Parses --changed-since, either an age like "90m", "6h" or "2d", or a local time like
+/// "2024-08-01 09:00"
+fn parse_changed_since(s: &str) -> anyhow::Result<chrono::DateTime<chrono::Local>> {
+    for (suffix, unit_secs) in [('m', 60), ('h', 60 * 60), ('d', 24 * 60 * 60)] {
+        if let Some(Ok(n)) = s.strip_suffix(suffix).map(str::parse::<u64>) {
+            return Ok(chrono::Local::now() - Duration::from_secs(n * unit_secs));
+        }
+    }
+    chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M")
+        .ok()
+        .and_then(|t| t.and_local_timezone(chrono::Local).earliest())
+        .ok_or(anyhow!(
+            "expected an age like 6h or a time like \"2024-08-01 09:00\""
+        ))
+}
+
+/// Every file under the backup dirs modified after `since`, for backing up just those. Excluded
+/// dirs aren't walked into, since most of the time goes to caches and build output.
+fn changed_files(
+    backup_dirs: &[String],
+    since: chrono::DateTime<chrono::Local>,
+    patterns: &[String],
+) -> Vec<String> {
+    let since = time::SystemTime::from(since);
+    let mut changed = Vec::new();
+    let mut unreadable = 0;
+    let mut pending: Vec<PathBuf> = backup_dirs.iter().map(PathBuf::from).collect();
+    while let Some(path) = pending.pop() {
+        let path_str = path.to_string_lossy();
+        if let Some((_, true)) = exclude_match::deciding_pattern(patterns, &path_str) {
+            continue;
+        }
+        let Ok(metadata) = fs::symlink_metadata(&path) else {
+            unreadable += 1;
+            continue;
+        };
+        if !metadata.is_dir() {
+            if metadata.modified().is_ok_and(|modified| modified > since) {
+                changed.push(path_str.into_owned());
+            }
+            continue;
+        }
+        match fs::read_dir(&path) {
+            Ok(entries) => pending.extend(entries.flatten().map(|entry| entry.path())),
+            Err(_) => unreadable += 1,
+        }
+    }
+    if unreadable > 0 {
+        log::warn!("Couldn't read {unreadable} paths while looking for changed files");
+    }
+    changed.sort_unstable();
+    changed
+}
+
+/// Logs how many files are under each backup dir and its biggest subdirs, to help find
+/// candidates for excludes. This doesn't apply the exclude patterns, so it's an upper bound.
+fn log_file_counts(backup_dirs: &[String]) {
+    let mut subdir_counts: Vec<(PathBuf, u64)> = Vec::new();
+    for dir in backup_dirs {
+        if !fs::symlink_metadata(dir).is_ok_and(|m| m.is_dir()) {
+            log::info!("1 file in {dir}");
+            continue;
+        }
+
+        let mut total: u64 = 0;
+        for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+            match entry.file_type() {
+                Ok(t) if t.is_dir() => {
+                    let count = count_files(&entry.path());
+                    total += count;
+                    subdir_counts.push((entry.path(), count));
+                }
+                Ok(_) => total += 1,
+                Err(_) => {}
+            }
+        }
+        log::info!("{total} files in {dir}");
+    }
+
+    subdir_counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    for (path, count) in subdir_counts.iter().take(FILE_COUNT_TOP_N) {
+        log::info!("  {count} files in {}", path.display());
+    }
+}
+
 fn pretty_duration(duration: Duration) -> String {
     let minute: u64 = 60;
     let hour: u64 = minute * 60;
@@ -101,6 +583,300 @@ fn pretty_duration(duration: Duration) -> String {
     }
 }
 
+/// Replaces the file with one with CREATED_FILE_MODE permissions holding `contents`. It's written
+/// next to the target and renamed over it, so a symlink at the path is replaced, not followed.
+fn write_private_file(path: &Path, contents: &str) -> anyhow::Result<()> {
+    replace_file(path, contents, CREATED_FILE_MODE)
+}
+
+/// Like write_private_file, but with SHARED_FILE_MODE permissions for files other users read
+fn write_shared_file(path: &Path, contents: &str) -> anyhow::Result<()> {
+    replace_file(path, contents, SHARED_FILE_MODE)
+}
+
+fn replace_file(path: &Path, contents: &str, mode: u32) -> anyhow::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(format!(".{}-{:016x}.tmp", process::id(), random_suffix()));
+    let tmp_path = path.with_file_name(tmp_name);
+    create_new_file(&tmp_path, contents, mode)
+        .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+    if let Err(e) = fs::rename(&tmp_path, path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e).with_context(|| format!("Failed to replace {}", path.display()));
+    }
+    Ok(())
+}
+
+/// Creates a file that mustn't exist yet with `mode` permissions on Unix and writes `contents`.
+/// Failing on an existing file means a symlink planted at the path isn't followed.
+fn create_new_file(path: &Path, contents: &str, mode: u32) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+/// A number other users can't guess, for temp file names
+fn random_suffix() -> u64 {
+    use std::hash::BuildHasher;
+    // RandomState is seeded from the OS's randomness
+    let nanos = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    std::collections::hash_map::RandomState::new().hash_one(nanos)
+}
+
+/// A file in the temp dir that's deleted when dropped
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    fn create(name: &str, contents: &str) -> anyhow::Result<Self> {
+        // The temp dir is usually shared, so the name has to be unpredictable and the file new
+        loop {
+            let path = env::temp_dir().join(format!(
+                "backuper-{name}-{}-{:016x}",
+                process::id(),
+                random_suffix()
+            ));
+            match create_new_file(&path, contents, CREATED_FILE_MODE) {
+                Ok(()) => return Ok(Self { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// The PID of the run that created a temp file, if it's one of ours
+fn temp_file_owner(file_name: &str) -> Option<u32> {
+    let rest = file_name.strip_prefix("backuper-")?;
+    let mut parts = rest.rsplitn(3, '-');
+    let _suffix = parts.next()?;
+    parts.next()?.parse().ok()
+}
+
+#[cfg(unix)]
+fn process_is_running(pid: u32) -> bool {
+    // Signal 0 only checks whether the process exists. EPERM still means it does.
+    let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    ret == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_is_running(pid: u32) -> bool {
+    // tasklist prints the process's row if it exists, or an INFO line otherwise. If it can't
+    // run, assume the process is running so the file is left alone.
+    match Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {pid}"), "/NH", "/FO", "CSV"])
+        .output()
+    {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).contains(&format!("\"{pid}\"")),
+        Err(_) => true,
+    }
+}
+
+/// Removes temp files left behind by runs that crashed or were killed
+fn remove_stale_temp_files() -> anyhow::Result<()> {
+    for entry in fs::read_dir(env::temp_dir())?.flatten() {
+        let Some(pid) = temp_file_owner(&entry.file_name().to_string_lossy()) else {
+            continue;
+        };
+        if pid == process::id() || process_is_running(pid) {
+            continue;
+        }
+        // One unreadable file shouldn't stop the rest from being cleaned up
+        let modified = match entry.metadata().and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                log::warn!("Skipping temp file {}: {e}", entry.path().display());
+                continue;
+            }
+        };
+        if modified.elapsed().unwrap_or_default() < STALE_TEMP_FILE_AGE {
+            continue;
+        }
+        match fs::remove_file(entry.path()) {
+            Ok(()) => log::info!("Removed stale temp file {}", entry.path().display()),
+            Err(e) => log::warn!("Failed to remove temp file {}: {e}", entry.path().display()),
+        }
+    }
+    Ok(())
+}
+
+/// Where restic keeps its cache, following its own defaults
+fn restic_cache_dir() -> anyhow::Result<PathBuf> {
+    if let Some(dir) = env::var_os("RESTIC_CACHE_DIR") {
+        return Ok(PathBuf::from(dir));
+    }
+    let cache_dir = if cfg!(windows) {
+        PathBuf::from(get_env_var("LOCALAPPDATA")?)
+    } else if cfg!(target_os = "macos") {
+        home_dir()?.join("Library/Caches")
+    } else if let Some(dir) = env::var_os("XDG_CACHE_HOME") {
+        PathBuf::from(dir)
+    } else {
+        home_dir()?.join(".cache")
+    };
+    Ok(cache_dir.join("restic"))
+}
+
+/// Where backuper keeps what it remembers between runs. BACKUPER_STATE_DIR overrides it.
+fn state_dir() -> anyhow::Result<PathBuf> {
+    if let Some(dir) = env::var_os("BACKUPER_STATE_DIR") {
+        return Ok(PathBuf::from(dir));
+    }
+    let state_dir = if cfg!(windows) {
+        PathBuf::from(get_env_var("LOCALAPPDATA")?)
+    } else if cfg!(target_os = "macos") {
+        home_dir()?.join("Library/Application Support")
+    } else if let Some(dir) = env::var_os("XDG_STATE_HOME") {
+        PathBuf::from(dir)
+    } else {
+        home_dir()?.join(".local/state")
+    };
+    Ok(state_dir.join("backuper"))
+}
+
+/// The dirs backuper and restic write to while running, which are pointless to back up
+fn own_dirs() -> anyhow::Result<Vec<PathBuf>> {
+    Ok(vec![restic_cache_dir()?, state_dir()?, env::temp_dir()])
+}
+
+fn last_run_path() -> anyhow::Result<PathBuf> {
+    Ok(state_dir()?.join("last-run.json"))
+}
+
+fn save_last_run(results: &[TaskResult]) -> anyhow::Result<()> {
+    let path = last_run_path()?;
+    fs::create_dir_all(state_dir()?)?;
+    write_private_file(&path, &serde_json::to_string_pretty(results)?)
+}
+
+/// Names of the tasks that succeeded in the last run, counting ones it skipped for having
+/// succeeded in the run before that
+fn load_completed_tasks() -> anyhow::Result<Vec<String>> {
+    let path = last_run_path()?;
+    let results: Vec<TaskResult> = match fs::read_to_string(&path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse {}", path.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(results
+        .into_iter()
+        .filter(|r| match &r.status {
+            TaskStatus::Succeeded => true,
+            TaskStatus::Skipped(reason) => reason == COMPLETED_SKIP_REASON,
+            _ => false,
+        })
+        .map(|r| r.name)
+        .collect())
+}
+
+/// The configured excludes plus the patterns of the repo's exclude tier and the tiers below it
+fn exclude_patterns_for(config: &ResticConfig) -> Vec<&'static str> {
+    let mut patterns = config_file::excludes().unwrap_or_else(|| EXCLUDE_PATTERNS.to_vec());
+    for (tier, tier_patterns) in TIER_EXCLUDE_PATTERNS {
+        if *tier <= config.exclude_tier {
+            patterns.extend(*tier_patterns);
+        }
+    }
+    patterns
+}
+
+/// Merges the repo's exclude patterns and the EXCLUDE_FILES, in order, into everything restic
+/// gets in its exclude file
+fn all_exclude_patterns(config: &ResticConfig) -> anyhow::Result<Vec<String>> {
+    let mut patterns: Vec<String> = exclude_patterns_for(config)
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+    for path in backup_dirs_to_strings(EXCLUDE_FILES)? {
+        match fs::read_to_string(&path) {
+            Ok(contents) => patterns.extend(contents.lines().map(str::to_owned)),
+            Err(e) => log::warn!("Skipping exclude file {path}: {e}"),
+        }
+    }
+    patterns.extend(TIME_MACHINE_EXCLUDES.get().into_iter().flatten().cloned());
+    if EXCLUDE_OWN_DIRS {
+        for dir in own_dirs()? {
+            log::info!("Excluding backuper's own dir {}", dir.display());
+            patterns.push(dir.to_string_lossy().into_owned());
+        }
+    }
+    Ok(patterns)
+}
+
+fn write_exclude_file(config: &ResticConfig) -> anyhow::Result<TempFile> {
+    TempFile::create("excludes", &all_exclude_patterns(config)?.join("\n"))
+}
+
+/// Logs whether the repo's excludes would leave out the path, and which pattern decided it
+fn explain_exclusion(config: &ResticConfig, via_wsl: bool, path: &str) -> anyhow::Result<()> {
+    // WSL backups only get the inline patterns, see backup_wsl
+    let patterns = if via_wsl {
+        exclude_patterns_for(config)
+            .iter()
+            .map(|p| p.to_string())
+            .collect()
+    } else {
+        all_exclude_patterns(config)?
+    };
+    let verdict = match exclude_match::deciding_pattern(&patterns, path) {
+        Some((pattern, true)) => format!("excluded by {pattern}"),
+        Some((pattern, false)) => format!("included, re-included by {pattern}"),
+        None => format!("included, none of the {} patterns match", patterns.len()),
+    };
+    log::info!("{path} in {} repo: {verdict}", config.name);
+    Ok(())
+}
+
+// Set once per run by load_time_machine_excludes
+static TIME_MACHINE_EXCLUDES: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Asks Time Machine which of the backup dirs, or the files and dirs directly in them, it skips
+fn load_time_machine_excludes(backup_dirs: &[String]) -> anyhow::Result<Vec<String>> {
+    if !program_exists("tmutil") {
+        log::warn!("tmutil not found, not importing Time Machine exclusions");
+        return Ok(Vec::new());
+    }
+
+    let mut paths = backup_dirs.to_vec();
+    for dir in backup_dirs {
+        let entries = fs::read_dir(dir).into_iter().flatten().flatten();
+        paths.extend(entries.map(|e| e.path().to_string_lossy().into_owned()));
+    }
+    let mut cmd = vec!["tmutil", "isexcluded"];
+    cmd.extend(paths.iter().map(String::as_str));
+    let output = sh(&cmd).output()?;
+
+    // Lines look like "[Excluded]    /Users/alex/Downloads"
+    let excluded: Vec<String> = output
+        .lines()
+        .filter_map(|line| line.strip_prefix("[Excluded]"))
+        .map(|path| path.trim().to_owned())
+        .collect();
+    for path in &excluded {
+        log::info!("Excluding {path}, like Time Machine does");
+    }
+    Ok(excluded)
+}
+
 fn gen_exclude_flags<'a>(patterns: &'a [&'a str]) -> Vec<&'a str> {
     patterns.iter().flat_map(|p| ["--exclude", p]).collect()
 }
@@ -114,6 +890,8 @@ struct ShBuilder<'a> {
     env: &'a [(&'a str, &'a str)],
     input: &'a str,
     check: bool,
+    timeout: Option<Duration>,
+    show_output: bool,
 }
 
 impl<'a> ShBuilder<'a> {
@@ -123,6 +901,8 @@ impl<'a> ShBuilder<'a> {
             env: &[],
             input: "",
             check: true,
+            timeout: None,
+            show_output: false,
         }
     }
 
@@ -141,243 +921,2482 @@ impl<'a> ShBuilder<'a> {
         self
     }
 
+    /// Also prints the command's output as it comes, for watching long commands
+    fn show_output(mut self) -> Self {
+        self.show_output = true;
+        self
+    }
+
+    /// Kills the command if it's still running after `timeout`, on top of any task timeout
+    fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
     fn run(self) -> anyhow::Result<()> {
+        self.output().map(|_| ())
+    }
+
+    /// Like `run`, but returns the command's stdout
+    fn output(self) -> anyhow::Result<String> {
         // Print command to run
         let cmd_str = self.cmd.join(" ");
         log::info!("Running: {cmd_str}");
 
+        let command_deadline = self.timeout.map(|t| time::Instant::now() + t);
+        let task_deadline = TASK_DEADLINE.get();
+        let deadline = command_deadline.into_iter().chain(task_deadline).min();
+
         // Spawn a new child process with the given command, args, and env vars
-        let mut child = Command::new(self.cmd[0])
+        let mut command = Command::new(self.cmd[0]);
+        command
             .args(&self.cmd[1..])
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
-            .envs(self.env.to_vec())
-            .spawn()?;
+            .envs(self.env.to_vec());
+        // A command that can time out gets its own process group, so whatever it started can be
+        // killed with it. It won't see Ctrl-C from the terminal then, so only those do.
+        #[cfg(unix)]
+        if deadline.is_some() {
+            use std::os::unix::process::CommandExt;
+            command.process_group(0);
+        }
+        let mut child = command.spawn()?;
 
-        // Write the input to the child process's stdin
-        child
-            .stdin
-            .as_mut()
-            .ok_or(anyhow!("Failed to get stdin"))?
-            .write_all(self.input.as_bytes())?;
+        // Drain the output pipes in the background so we can keep an eye on the task deadline
+        let stdout_reader =
+            spawn_pipe_reader(child.stdout.take(), self.show_output.then(io::stdout));
+        let stderr_reader =
+            spawn_pipe_reader(child.stderr.take(), self.show_output.then(io::stderr));
+
+        // Feed stdin from another thread too, since the child may fill up its output pipes before
+        // it's read all of a long files-from list. Dropping stdin at the end closes it.
+        let mut stdin = child.stdin.take().ok_or(anyhow!("Failed to get stdin"))?;
+        let input = self.input.as_bytes().to_vec();
+        let stdin_writer = thread::spawn(move || stdin.write_all(&input));
+
+        let status = match deadline {
+            Some(deadline) => wait_until(&mut child, deadline)?,
+            None => Some(child.wait()?),
+        };
+        let killed = status.is_none();
+        let stdout = join_io_thread(stdout_reader, killed).unwrap_or_default();
+        let stderr = join_io_thread(stderr_reader, killed).unwrap_or_default();
+        match join_io_thread(stdin_writer, killed) {
+            // The child exiting without reading all its input is up to the exit status to report
+            Some(Err(e)) if e.kind() != io::ErrorKind::BrokenPipe => {
+                return Err(anyhow::Error::new(e).context("Failed to write stdin"))
+            }
+            _ => {}
+        }
 
-        let output = child.wait_with_output()?;
+        let Some(status) = status else {
+            let reason = match (self.timeout, command_deadline) {
+                (Some(timeout), Some(deadline)) if task_deadline.is_none_or(|t| deadline <= t) => {
+                    format!("Command timed out after {}", pretty_duration(timeout))
+                }
+                _ => "Task timed out".to_owned(),
+            };
+            let stderr = String::from_utf8_lossy(&stderr);
+            return Err(anyhow!(
+                "{reason}, killed: {cmd_str}\n{}",
+                stderr.trim_end()
+            ));
+        };
 
         // If checking is enabled and the process failed, return an error
-        if self.check && !output.status.success() {
-            let stderr_str = String::from_utf8(output.stderr)?;
-            return Err(anyhow!(stderr_str));
+        if self.check && !status.success() {
+            return Err(ShError {
+                code: status.code(),
+                stderr: String::from_utf8(stderr)?,
+            }
+            .into());
         }
 
-        Ok(())
+        Ok(String::from_utf8(stdout)?)
     }
 }
 
-fn get_env_var(var: &str) -> anyhow::Result<String> {
-    env::var(var).with_context(|| format!("Env var not found: {}", var))
+/// A command that ran to completion but exited unsuccessfully
+#[derive(Debug)]
+struct ShError {
+    code: Option<i32>,
+    stderr: String,
 }
 
-fn notify(subject: &str, body: &str) -> anyhow::Result<()> {
-    // Grab credentials
-    let email_address = get_env_var("BACKUPER_EMAIL_ADDRESS")?;
-    let email_password = get_env_var("BACKUPER_EMAIL_PASSWORD")?;
-
-    // Build the email
-    let email = Message::builder()
-        .from(format!("Backup Script <{email_address}>").parse()?)
-        .to(format!("Alex Ozer <{email_address}>").parse()?)
-        .subject(subject)
-        .header(ContentType::TEXT_PLAIN)
-        .body(body.to_owned())?;
-
-    let creds = Credentials::new(email_address, email_password);
+impl fmt::Display for ShError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.stderr)
+    }
+}
 
-    // Open a remote connection to gmail
-    let mailer = SmtpTransport::relay("smtp.gmail.com")?
-        .credentials(creds)
-        .build();
+impl std::error::Error for ShError {}
 
-    // Send the email
-    mailer.send(&email)?;
-    Ok(())
+thread_local! {
+    // Set by `try_task` while a task with a timeout is running
+    static TASK_DEADLINE: Cell<Option<time::Instant>> = const { Cell::new(None) };
 }
 
-fn try_task<F>(name: &str, func: F, error_list: &mut Vec<String>)
+/// Collects everything from the pipe, echoing it to `echo` as well if given
+fn spawn_pipe_reader<R, W>(pipe: Option<R>, mut echo: Option<W>) -> thread::JoinHandle<Vec<u8>>
 where
-    F: FnOnce() -> anyhow::Result<()>,
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
 {
-    log::info!("Starting task: {name}");
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let Some(pipe) = pipe else {
+            return buf;
+        };
+        // Read line by line so SIGUSR1 can report the latest line
+        let mut reader = BufReader::new(pipe);
+        loop {
+            let line_start = buf.len();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    let line = &buf[line_start..];
+                    if let Some(echo) = echo.as_mut() {
+                        let _ = echo.write_all(line).and_then(|_| echo.flush());
+                    }
+                    progress::record_output_line(&String::from_utf8_lossy(line));
+                }
+            }
+        }
+        buf
+    })
+}
 
-    let start = time::Instant::now();
-    let result = func();
-    let dur = start.elapsed();
-    let pretty_dur = pretty_duration(dur);
+/// Joins a thread reading or writing the child's pipes, or None if it panicked. After a kill this
+/// gives up after a few seconds, since something that escaped the kill can hold the pipe open.
+fn join_io_thread<T>(handle: thread::JoinHandle<T>, killed: bool) -> Option<T> {
+    if killed {
+        let give_up = time::Instant::now() + Duration::from_secs(5);
+        while !handle.is_finished() {
+            if time::Instant::now() >= give_up {
+                return None;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+    handle.join().ok()
+}
 
-    match result {
-        Ok(()) => {
-            log::info!("Task succeeded in {pretty_dur}: {name}");
+/// Waits for the child to exit, killing it if it's still running at the deadline.
+/// Returns None if the child was killed.
+fn wait_until(child: &mut Child, deadline: time::Instant) -> anyhow::Result<Option<ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
         }
-        Err(e) => {
-            let err_str = format!("[{} in {pretty_dur}] {}", name, e);
-            error_list.push(err_str);
-            log::error!("Task failed in {pretty_dur}: {name}");
+        if time::Instant::now() >= deadline {
+            kill_tree(child)?;
+            child.wait()?;
+            return Ok(None);
         }
+        thread::sleep(Duration::from_millis(100));
     }
 }
 
-fn do_windows_upgrades() -> anyhow::Result<()> {
-    sh(&["choco", "upgrade", "all"]).run()?;
-    sh(&["wsl.exe", "sudo", "apt", "update"]).run()?;
-    sh(&["wsl.exe", "sudo", "apt", "upgrade", "-y"]).run()?;
-    sh(&["wsl.exe", "/home/linuxbrew/.linuxbrew/bin/brew", "upgrade"]).run()?;
-    Ok(())
+/// Kills the child along with the processes it started, which could otherwise keep running and
+/// hold its pipes open
+fn kill_tree(child: &mut Child) -> io::Result<()> {
+    // The child leads its own process group, see ShBuilder::output
+    #[cfg(unix)]
+    if unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL) } == 0 {
+        return Ok(());
+    }
+    #[cfg(windows)]
+    {
+        let pid = child.id().to_string();
+        let _ = Command::new("taskkill")
+            .args(["/F", "/T", "/PID", &pid])
+            .output();
+    }
+    child.kill()
 }
 
-fn do_macos_upgrades() -> anyhow::Result<()> {
-    sh(&["brew", "upgrade"]).run()
+fn start_heartbeat(path: &'static str) {
+    thread::spawn(move || loop {
+        if progress::any_task_running() {
+            let now = chrono::Local::now().to_rfc3339();
+            if let Err(e) = write_shared_file(Path::new(path), &now) {
+                log::warn!("Failed to write heartbeat file {path}: {e}");
+            }
+        }
+        thread::sleep(HEARTBEAT_INTERVAL);
+    });
 }
 
-fn restic_config_to_env(config: &ResticConfig) -> Vec<(&str, &str)> {
-    let mut env_pairs: Vec<(&str, &str)> = vec![
-        ("RESTIC_REPOSITORY", &config.restic_repository),
-        ("RESTIC_PASSWORD", &config.restic_password),
-    ];
-    if let Some(key_id) = config.aws_access_key_id.as_ref() {
-        env_pairs.push(("AWS_ACCESS_KEY_ID", key_id));
-    }
-    if let Some(secret) = config.aws_secret_access_key.as_ref() {
-        env_pairs.push(("AWS_SECRET_ACCESS_KEY", secret));
-    }
-    env_pairs
+fn get_env_var(var: &str) -> anyhow::Result<String> {
+    env::var(var).with_context(|| format!("Env var not found: {}", var))
 }
 
-fn backup_filesystem_to(
-    file_patterns: &[BackupDir],
-    config: &ResticConfig,
-    extra_restic_args: &[&str],
-) -> anyhow::Result<()> {
-    let mut restic_args = vec!["restic", "backup", "--files-from", "-"];
-    restic_args.extend(extra_restic_args);
-    restic_args.extend(gen_exclude_flags(EXCLUDE_PATTERNS));
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "status", content = "message", rename_all = "snake_case")]
+enum TaskStatus {
+    Succeeded,
+    Failed(String),
+    // Finished, but with problems worth mentioning, like a snapshot missing unreadable files
+    Warned(String),
+    Skipped(String),
+}
+
+#[derive(Serialize, Deserialize)]
+struct TaskResult {
+    name: String,
+    duration: Duration,
+    #[serde(flatten)]
+    status: TaskStatus,
+}
+
+/// The error lines for the email, one per failed task
+fn task_errors(results: &[TaskResult]) -> Vec<String> {
+    results
+        .iter()
+        .filter_map(|r| match &r.status {
+            TaskStatus::Failed(e) => Some(format!(
+                "[{} in {}] {}",
+                r.name,
+                pretty_duration(r.duration),
+                e
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+// Set per pass with --profiles, see profile_env_var
+static RUN_PROFILE: Mutex<Option<String>> = Mutex::new(None);
+
+fn set_run_profile(profile: Option<String>) {
+    if let Some(profile) = profile.as_ref() {
+        log::info!("Running profile {profile}");
+    }
+    *RUN_PROFILE.lock().unwrap_or_else(|e| e.into_inner()) = profile;
+}
+
+fn run_profile() -> Option<String> {
+    RUN_PROFILE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+/// Tells apart the same task run for different profiles in the report
+fn task_name_prefix() -> String {
+    run_profile()
+        .map(|profile| format!("[{profile}] "))
+        .unwrap_or_default()
+}
+
+/// Reads a BACKUPER_ setting, preferring the current profile's version if there is one, e.g.
+/// BACKUPER_ALICE_RESTIC_REPOSITORY over BACKUPER_RESTIC_REPOSITORY for profile "alice"
+fn profile_env_var(var: &str) -> anyhow::Result<String> {
+    if let Some(profile) = run_profile() {
+        let profile_var = var.replacen(
+            "BACKUPER_",
+            &format!("BACKUPER_{}_", profile.to_uppercase().replace('-', "_")),
+            1,
+        );
+        if let Ok(value) = env::var(profile_var) {
+            return Ok(value);
+        }
+    }
+    get_env_var(var)
+}
+
+/// Logs the outcome of a task, with separate fields for it under journald
+fn log_task_result(level: log::Level, message: &str, name: &str, dur: Duration, status: &str) {
+    if journal::is_active() {
+        journal::log_task(level, message, name, dur.as_millis(), status);
+    } else {
+        log::log!(level, "{message}");
+    }
+}
+
+fn skip_task(name: &str, reason: &str, results: &mut Vec<TaskResult>) {
+    let name = &format!("{}{name}", task_name_prefix());
+    log_task_result(
+        log::Level::Info,
+        &format!("Task skipped, {reason}: {name}"),
+        name,
+        Duration::ZERO,
+        "skipped",
+    );
+    results.push(TaskResult {
+        name: name.to_owned(),
+        duration: Duration::ZERO,
+        status: TaskStatus::Skipped(reason.to_owned()),
+    });
+}
+
+// Set from --strict, which makes warnings fail their task instead
+static STRICT_WARNINGS: AtomicBool = AtomicBool::new(false);
+
+// Set from --skip-completed to the tasks that already succeeded in the last run
+static COMPLETED_TASKS: OnceLock<Vec<String>> = OnceLock::new();
+static COMPLETED_SKIP_REASON: &str = "succeeded in the last run";
+
+fn is_warning(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref(),
+        Some(restic_error::ResticError::IncompleteSnapshot { .. })
+    )
+}
+
+fn try_task<F>(name: &str, func: F, results: &mut Vec<TaskResult>)
+where
+    F: FnOnce() -> anyhow::Result<()>,
+{
+    let timeout = TASK_TIMEOUTS
+        .iter()
+        .find(|(task_name, _)| *task_name == name)
+        .map(|(_, timeout)| *timeout);
+
+    let unprefixed_name = name;
+    let name = &format!("{}{name}", task_name_prefix());
+    if COMPLETED_TASKS
+        .get()
+        .is_some_and(|tasks| tasks.iter().any(|task| task == name))
+    {
+        skip_task(unprefixed_name, COMPLETED_SKIP_REASON, results);
+        return;
+    }
+    log::info!("Starting task: {name}");
+
+    let start = time::Instant::now();
+    TASK_DEADLINE.set(timeout.map(|t| start + t));
+    progress::task_started(name);
+    let result = func();
+    progress::task_finished(name);
+    TASK_DEADLINE.set(None);
+    let dur = start.elapsed();
+    let pretty_dur = pretty_duration(dur);
+
+    let (status, level, message, status_field) = match result {
+        Ok(()) => (
+            TaskStatus::Succeeded,
+            log::Level::Info,
+            "Task succeeded",
+            "succeeded",
+        ),
+        Err(e) if is_warning(&e) && !STRICT_WARNINGS.load(Ordering::Relaxed) => (
+            TaskStatus::Warned(e.to_string()),
+            log::Level::Warn,
+            "Task finished with warnings",
+            "warned",
+        ),
+        Err(e) => (
+            TaskStatus::Failed(e.to_string()),
+            log::Level::Error,
+            "Task failed",
+            "failed",
+        ),
+    };
+    log_task_result(
+        level,
+        &format!("{message} in {pretty_dur}: {name}"),
+        name,
+        dur,
+        status_field,
+    );
+    results.push(TaskResult {
+        name: name.to_owned(),
+        duration: dur,
+        status,
+    });
+}
+
+fn do_windows_upgrades() -> anyhow::Result<()> {
+    sh(&["choco", "upgrade", "all"]).run()?;
+    sh(&["wsl.exe", "sudo", "apt", "update"]).run()?;
+    sh(&["wsl.exe", "sudo", "apt", "upgrade", "-y"]).run()?;
+    sh(&["wsl.exe", "/home/linuxbrew/.linuxbrew/bin/brew", "upgrade"])
+        .timeout(BREW_UPGRADE_TIMEOUT)
+        .run()?;
+    Ok(())
+}
+
+fn do_macos_upgrades() -> anyhow::Result<()> {
+    sh(&["brew", "upgrade"]).timeout(BREW_UPGRADE_TIMEOUT).run()
+}
+
+fn do_linux_upgrades() -> anyhow::Result<()> {
+    if program_exists("apt") {
+        sh(&["sudo", "apt", "update"]).run()?;
+        sh(&["sudo", "apt", "upgrade", "-y"]).run()?;
+    } else if program_exists("pacman") {
+        sh(&["sudo", "pacman", "-Syu", "--noconfirm"]).run()?;
+    } else {
+        log::warn!("Neither apt nor pacman found, skipping system package upgrades");
+    }
+    if program_exists("brew") {
+        sh(&["brew", "upgrade"])
+            .timeout(BREW_UPGRADE_TIMEOUT)
+            .run()?;
+    }
+    Ok(())
+}
+
+fn env_profile_vars(name: &str) -> anyhow::Result<&'static [(&'static str, &'static str)]> {
+    ENV_PROFILES
+        .iter()
+        .find(|(profile, _)| *profile == name)
+        .map(|(_, vars)| *vars)
+        .ok_or(anyhow!("Unknown env profile: {name}"))
+}
+
+/// Checks that the repo's env profiles exist and don't step on each other's or the repo's vars
+fn validate_env_profiles(config: &ResticConfig) -> anyhow::Result<()> {
+    let mut seen: Vec<(&str, &str)> = restic_own_env(config)
+        .into_iter()
+        .map(|(key, _)| (key, "the repo's own settings"))
+        .collect();
+    for profile in &config.env_profiles {
+        for (key, _) in env_profile_vars(profile)? {
+            if let Some((_, source)) = seen.iter().find(|(k, _)| k == key) {
+                return Err(anyhow!(
+                    "Env profile {profile} sets {key}, already set by {source} for the {} repo",
+                    config.name
+                ));
+            }
+            seen.push((key, profile));
+        }
+    }
+    Ok(())
+}
+
+fn restic_own_env(config: &ResticConfig) -> Vec<(&str, &str)> {
+    let mut env_pairs: Vec<(&str, &str)> = vec![("RESTIC_REPOSITORY", &config.restic_repository)];
+    match config.password_command.as_ref() {
+        Some(command) => env_pairs.push(("RESTIC_PASSWORD_COMMAND", command)),
+        None => env_pairs.push(("RESTIC_PASSWORD", &config.restic_password)),
+    }
+    if let Some(key_id) = config.aws_access_key_id.as_ref() {
+        env_pairs.push(("AWS_ACCESS_KEY_ID", key_id));
+    }
+    if let Some(secret) = config.aws_secret_access_key.as_ref() {
+        env_pairs.push(("AWS_SECRET_ACCESS_KEY", secret));
+    }
+    env_pairs
+}
+
+// Profiles were checked by validate_env_profiles when the config was built
+fn restic_config_to_env(config: &ResticConfig) -> Vec<(&str, &str)> {
+    let mut env_pairs = restic_own_env(config);
+    for profile in &config.env_profiles {
+        env_pairs.extend(env_profile_vars(profile).unwrap_or_default());
+    }
+    env_pairs
+}
+
+/// Flags that apply to every restic command run against the repo
+fn restic_repo_flags(config: &ResticConfig) -> Vec<&str> {
+    let mut flags = Vec::new();
+    if let Some(retry_lock) = config.retry_lock.as_ref() {
+        flags.extend(["--retry-lock", retry_lock]);
+    }
+    flags
+}
+
+/// Preset bundles of restic tuning flags, so repos don't need every knob set individually
+#[derive(Clone, Copy)]
+enum PerfProfile {
+    /// Fast disk or LAN: read more files at once and write fewer, bigger packs
+    FastLocal,
+    /// Slow uplink: compress as hard as possible so less data goes over the wire
+    LowBandwidth,
+    /// Weak CPU: skip compression and read one file at a time
+    LowCpu,
+}
+
+#[derive(Default)]
+struct Tuning {
+    read_concurrency: Option<u32>,
+    pack_size_mib: Option<u32>,
+    compression: Option<&'static str>,
+}
+
+impl std::str::FromStr for PerfProfile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "fast-local" => Ok(PerfProfile::FastLocal),
+            "low-bandwidth" => Ok(PerfProfile::LowBandwidth),
+            "low-cpu" => Ok(PerfProfile::LowCpu),
+            _ => Err(anyhow!("Unknown performance profile: {s}")),
+        }
+    }
+}
+
+/// Explicit tuning flags for the cloud repo, checked against what restic accepts
+fn env_tuning() -> anyhow::Result<Tuning> {
+    let read_concurrency = parse_env_var("BACKUPER_READ_CONCURRENCY")?;
+    if read_concurrency == Some(0) {
+        return Err(anyhow!("BACKUPER_READ_CONCURRENCY must be at least 1"));
+    }
+    let pack_size_mib = parse_env_var("BACKUPER_PACK_SIZE_MIB")?;
+    if pack_size_mib.is_some_and(|mib| !(4..=128).contains(&mib)) {
+        return Err(anyhow!("BACKUPER_PACK_SIZE_MIB must be between 4 and 128"));
+    }
+    let compression = match profile_env_var("BACKUPER_COMPRESSION").ok().as_deref() {
+        None => None,
+        Some("auto") => Some("auto"),
+        Some("off") => Some("off"),
+        Some("max") => Some("max"),
+        Some(other) => {
+            return Err(anyhow!(
+                "BACKUPER_COMPRESSION must be auto, off or max, not {other}"
+            ))
+        }
+    };
+    Ok(Tuning {
+        read_concurrency,
+        pack_size_mib,
+        compression,
+    })
+}
+
+impl PerfProfile {
+    fn tuning(self) -> Tuning {
+        match self {
+            PerfProfile::FastLocal => Tuning {
+                read_concurrency: Some(8),
+                pack_size_mib: Some(64),
+                compression: Some("auto"),
+            },
+            PerfProfile::LowBandwidth => Tuning {
+                read_concurrency: Some(2),
+                pack_size_mib: None,
+                compression: Some("max"),
+            },
+            PerfProfile::LowCpu => Tuning {
+                read_concurrency: Some(1),
+                pack_size_mib: None,
+                compression: Some("off"),
+            },
+        }
+    }
+}
+
+// Set from --note, already sanitized into a tag
+static NOTE_TAG: OnceLock<String> = OnceLock::new();
+static NOTE_TAG_PREFIX: &str = "note:";
+
+/// Turns a free-text note into a tag restic won't mangle, e.g. "Before macOS 15!" into
+/// "note:before-macos-15"
+fn note_to_tag(note: &str) -> anyhow::Result<String> {
+    let words: Vec<String> = note
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    if words.is_empty() {
+        return Err(anyhow!("Note has nothing to put in a tag: {note}"));
+    }
+    Ok(format!("{NOTE_TAG_PREFIX}{}", words.join("-")))
+}
+
+// How many remote repos are being backed up at once, set by do_backup_windows
+static CONCURRENT_REPOS: AtomicUsize = AtomicUsize::new(1);
+
+/// The repo's own upload limit, or its share of UPLOAD_BUDGET_KIB if that's lower
+fn upload_limit_kib(config: &ResticConfig) -> Option<u32> {
+    let budget = UPLOAD_BUDGET_KIB.filter(|_| !config.local);
+    let share = budget.map(|budget| {
+        let processes = CONCURRENT_REPOS.load(Ordering::Relaxed) * config.parallel_backups.max(1);
+        (budget / processes as u32).max(1)
+    });
+    match (config.limit_upload_kib, share) {
+        (Some(limit), Some(share)) => Some(limit.min(share)),
+        (limit, share) => limit.or(share),
+    }
+}
+
+fn restic_backup_flags(config: &ResticConfig) -> Vec<String> {
+    let mut flags = Vec::new();
+    if let Some(tag) = NOTE_TAG.get() {
+        flags.extend(["--tag".into(), tag.clone()]);
+    }
+    if config.ignore_inode {
+        flags.push("--ignore-inode".into());
+    }
+    if config.ignore_ctime {
+        flags.push("--ignore-ctime".into());
+    }
+
+    // Explicit settings win over the profile's
+    let preset = config.profile.map(PerfProfile::tuning).unwrap_or_default();
+    let tuning = &config.tuning;
+    if let Some(n) = tuning.read_concurrency.or(preset.read_concurrency) {
+        flags.extend(["--read-concurrency".into(), n.to_string()]);
+    }
+    if let Some(mib) = tuning.pack_size_mib.or(preset.pack_size_mib) {
+        flags.extend(["--pack-size".into(), mib.to_string()]);
+    }
+    if let Some(compression) = tuning.compression.or(preset.compression) {
+        flags.extend(["--compression".into(), compression.into()]);
+    }
+    if let Some(limit) = upload_limit_kib(config) {
+        flags.extend(["--limit-upload".into(), limit.to_string()]);
+    }
+    flags
+}
+
+/// What restic reads with `--files-from -`, one dir or file per line as is
+fn files_from_input(backup_dirs: &[String]) -> String {
+    backup_dirs.join("\n")
+}
+
+/// What restic's `--json` backup summaries add up to, for logging
+#[derive(Default)]
+struct BackupSummary {
+    files: u64,
+    new_files: u64,
+    bytes_added: u64,
+}
+
+impl BackupSummary {
+    /// Adds the summary lines in a backup's output
+    fn add(&mut self, output: &str) {
+        for line in output.lines() {
+            let Ok(msg) = serde_json::from_str::<serde_json::Value>(line) else {
+                continue;
+            };
+            if msg["message_type"] == "summary" {
+                self.files += msg["total_files_processed"].as_u64().unwrap_or_default();
+                self.new_files += msg["files_new"].as_u64().unwrap_or_default();
+                self.bytes_added += msg["data_added"].as_u64().unwrap_or_default();
+            }
+        }
+    }
+
+    fn of(output: &str) -> Self {
+        let mut summary = Self::default();
+        summary.add(output);
+        summary
+    }
+}
+
+impl fmt::Display for BackupSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} files, {} new, {:.1} MB added",
+            self.files,
+            self.new_files,
+            self.bytes_added as f64 / 1e6
+        )
+    }
+}
+
+fn run_restic_backup(
+    backup_dirs: &[String],
+    config: &ResticConfig,
+    extra_restic_args: &[&str],
+) -> anyhow::Result<String> {
+    // JSON so the status lines can be reported on SIGUSR1 and the summary logged
+    let mut restic_args = vec!["restic", "backup", "--json", "--files-from", "-"];
+    restic_args.extend(extra_restic_args);
+    restic_args.extend(changed_since_tag_flags());
+    restic_args.extend(restic_repo_flags(config));
+    let backup_flags = restic_backup_flags(config);
+    restic_args.extend(backup_flags.iter().map(String::as_str));
+
+    let exclude_file = write_exclude_file(config)?;
+    let exclude_file_path = exclude_file.path.to_string_lossy();
+    if DRY_RUN.load(Ordering::Relaxed) {
+        log::info!(
+            "Excludes for {}:\n{}",
+            config.name,
+            fs::read_to_string(&exclude_file.path)?
+        );
+    }
+    restic_args.extend(["--exclude-file", &exclude_file_path]);
+
+    let input = files_from_input(backup_dirs);
+    let env = restic_config_to_env(config);
+    sh(&restic_args)
+        .env(&env)
+        .input(&input)
+        .output()
+        .map_err(|e| restic_error::classify(e, &config.restic_repository))
+}
+
+// Set from --dry-run
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// restic's --dry-run if this is a dry run, for backups and forgets to pass on
+fn dry_run_flag() -> &'static [&'static str] {
+    if DRY_RUN.load(Ordering::Relaxed) {
+        &["--dry-run"]
+    } else {
+        &[]
+    }
+}
+
+// Set from --changed-since, when the filesystem backups only get the files changed since then
+static CHANGED_SINCE: Mutex<Option<chrono::DateTime<chrono::Local>>> = Mutex::new(None);
+const CHANGED_SINCE_TAG: &str = "changed-since";
+
+fn changed_since() -> Option<chrono::DateTime<chrono::Local>> {
+    *CHANGED_SINCE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Tags the snapshot as partial if this is a --changed-since run
+fn changed_since_tag_flags() -> &'static [&'static str] {
+    if changed_since().is_some() {
+        &["--tag", CHANGED_SINCE_TAG]
+    } else {
+        &[]
+    }
+}
+
+/// Whether a --changed-since backup of these paths has nothing to do
+fn nothing_changed(paths: &[String]) -> bool {
+    changed_since().is_some() && paths.is_empty()
+}
+
+// Set from --checkpoint, see backup_filesystem_checkpointed
+static CHECKPOINT_BACKUPS: AtomicBool = AtomicBool::new(false);
+
+/// For seeding a new repo: backs up one dir at a time as its own snapshot tagged "checkpoint", so
+/// a failure hours in keeps the dirs that finished as complete snapshots. Then a normal snapshot
+/// of everything follows, which is quick since the data is already in the repo. The cost is an
+/// extra rescan of every dir and checkpoint snapshots that have to be forgotten later.
+fn backup_filesystem_checkpointed(
+    backup_dirs: &[String],
+    config: &ResticConfig,
+    extra_restic_args: &[&str],
+) -> anyhow::Result<()> {
+    let args = [extra_restic_args, &["--tag", "checkpoint"]].concat();
+    for (i, dir) in backup_dirs.iter().enumerate() {
+        log::info!("Checkpoint {}/{}: {dir}", i + 1, backup_dirs.len());
+        run_restic_backup(std::slice::from_ref(dir), config, &args)
+            .with_context(|| format!("Checkpoint backup of {dir} failed"))?;
+    }
+
+    let output = run_restic_backup(backup_dirs, config, extra_restic_args)?;
+    log::info!(
+        "Backed up local filesystem to {} after {} checkpoints: {}",
+        config.restic_repository,
+        backup_dirs.len(),
+        BackupSummary::of(&output)
+    );
+    Ok(())
+}
+
+fn backup_filesystem_to(
+    backup_dirs: &[String],
+    config: &ResticConfig,
+    extra_restic_args: &[&str],
+) -> anyhow::Result<()> {
+    if CHECKPOINT_BACKUPS.load(Ordering::Relaxed) && backup_dirs.len() > 1 {
+        return backup_filesystem_checkpointed(backup_dirs, config, extra_restic_args);
+    }
+    if config.parallel_backups > 1 && backup_dirs.len() > 1 {
+        return backup_filesystem_split(backup_dirs, config, extra_restic_args);
+    }
+
+    let output = run_restic_backup(backup_dirs, config, extra_restic_args)?;
+    log::info!(
+        "Backed up local filesystem to {}: {}",
+        config.restic_repository,
+        BackupSummary::of(&output)
+    );
+    Ok(())
+}
+
+/// Splits the dirs between several concurrent restic backups, one snapshot each. The snapshots
+/// share a run-<time> tag so they can be found together later.
+fn backup_filesystem_split(
+    backup_dirs: &[String],
+    config: &ResticConfig,
+    extra_restic_args: &[&str],
+) -> anyhow::Result<()> {
+    let degree = config.parallel_backups.min(backup_dirs.len());
+    let mut chunks = vec![Vec::new(); degree];
+    for (i, dir) in backup_dirs.iter().enumerate() {
+        chunks[i % degree].push(dir.clone());
+    }
+
+    let run_tag = format!("run-{}", chrono::Local::now().format("%Y%m%dT%H%M%S"));
+    let args = [extra_restic_args, &["--tag", &run_tag]].concat();
+    let deadline = TASK_DEADLINE.get();
+    let outputs: Vec<anyhow::Result<String>> = thread::scope(|scope| {
+        let handles: Vec<_> = chunks
+            .iter()
+            .map(|chunk| {
+                let args = &args;
+                scope.spawn(move || {
+                    TASK_DEADLINE.set(deadline);
+                    run_restic_backup(chunk, config, args)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| {
+                h.join()
+                    .unwrap_or_else(|_| Err(anyhow!("Backup thread panicked")))
+            })
+            .collect()
+    });
+
+    // Add up restic's per-snapshot summaries
+    let mut summary = BackupSummary::default();
+    let mut first_err = None;
+    for output in outputs {
+        let output = match output {
+            Ok(output) => output,
+            Err(e) => {
+                log::error!("Split backup failed: {e}");
+                first_err.get_or_insert(e);
+                continue;
+            }
+        };
+        summary.add(&output);
+    }
+    if let Some(e) = first_err {
+        return Err(e);
+    }
+
+    log::info!(
+        "Backed up local filesystem to {} in {degree} snapshots tagged {run_tag}: {summary}",
+        config.restic_repository
+    );
+    Ok(())
+}
+
+/// Dry-runs a backup and logs the files it would add or modify compared to the latest snapshot.
+/// A dry run can't see deleted files, `restic diff` between the snapshots afterwards shows those.
+fn preview_filesystem_backup(
+    backup_dirs: &[String],
+    config: &ResticConfig,
+    extra_restic_args: &[&str],
+) -> anyhow::Result<()> {
+    let args = [extra_restic_args, &["--dry-run", "--verbose"]].concat();
+    let stdout = run_restic_backup(backup_dirs, config, &args)?;
+
+    let mut added = 0;
+    let mut modified = 0;
+    for line in stdout.lines() {
+        let Ok(msg) = serde_json::from_str::<serde_json::Value>(line) else {
+            continue;
+        };
+        if msg["message_type"] != "verbose_status" {
+            continue;
+        }
+        let item = msg["item"].as_str().unwrap_or_default();
+        match msg["action"].as_str() {
+            Some("new") => {
+                added += 1;
+                log::info!("  added: {item}");
+            }
+            Some("modified") => {
+                modified += 1;
+                log::info!("  modified: {item}");
+            }
+            _ => {}
+        }
+    }
+
+    log::info!(
+        "Backing up to {} would add {added} and modify {modified} files",
+        config.restic_repository
+    );
+    Ok(())
+}
+
+// We don't want bash/zsh to try expanding our exclude glob patterns
+static WSL_RESTIC: &[&str] = &[
+    "wsl.exe",
+    "--shell-type",
+    "none",
+    "/home/linuxbrew/.linuxbrew/bin/restic",
+];
+static WSL_HOME: &str = "/home/alex";
+
+/// What the WSL backup covers: the home dir, or with --changed-since the files in it that changed.
+/// changed_files only walks the Windows side, so those are found from within WSL.
+fn wsl_backup_paths() -> anyhow::Result<Vec<String>> {
+    let Some(since) = changed_since() else {
+        return Ok(vec![WSL_HOME.to_owned()]);
+    };
+    let newer_than = format!("@{}", since.timestamp());
+    // find fails if some dirs are unreadable, which shouldn't lose the files it did find
+    let output = sh(&[
+        "wsl.exe",
+        "--shell-type",
+        "none",
+        "find",
+        WSL_HOME,
+        "-newermt",
+        &newer_than,
+        "!",
+        "-type",
+        "d",
+    ])
+    .check(false)
+    .output()?;
+    let files: Vec<String> = output.lines().map(str::to_owned).collect();
+    log::info!("{} WSL files changed since {since}", files.len());
+    Ok(files)
+}
+
+// Securely pass environment variables to WSL (I think...)
+fn wslenv_for(config: &ResticConfig) -> String {
+    let mut wslenv = get_env_var("WSLENV").unwrap_or_default();
+    wslenv.push(':');
+    wslenv.push_str(
+        &restic_config_to_env(config)
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect::<Vec<&str>>()
+            .join(":"),
+    );
+    wslenv
+}
+
+fn backup_wsl(config: &ResticConfig, paths: &[String]) -> anyhow::Result<()> {
+    // In case I forgot to kill `restic mount`, don't try to backup the mountpoint... ugh
+    sh(&["wsl.exe", "killall", "restic"]).check(false).run()?;
+
+    let wslenv = wslenv_for(config);
+    let mut env = restic_config_to_env(config);
+    env.push(("WSLENV", &wslenv));
+
+    // Call restic in WSL
+    let mut args = WSL_RESTIC.to_vec();
+    args.extend(["backup", "--json", "--files-from", "-", "--tag", "WSL"]);
+    args.extend(changed_since_tag_flags());
+    args.extend(restic_repo_flags(config));
+    let backup_flags = restic_backup_flags(config);
+    args.extend(backup_flags.iter().map(String::as_str));
+    // The merged exclude file lives on the Windows side, so WSL only gets the inline patterns
+    let patterns = exclude_patterns_for(config);
+    args.extend(gen_exclude_flags(&patterns));
+    args.extend(dry_run_flag());
+
+    let input = files_from_input(paths);
+    let output = sh(&args)
+        .env(&env)
+        .input(&input)
+        .output()
+        .map_err(|e| restic_error::classify(e, &config.restic_repository))?;
+    log::info!(
+        "Backed up WSL filesystem to {}: {}",
+        config.restic_repository,
+        BackupSummary::of(&output)
+    );
+    Ok(())
+}
+
+/// Runs a restic subcommand against the repo, through WSL if needed, and returns its stdout
+fn restic_output(
+    config: &ResticConfig,
+    via_wsl: bool,
+    restic_args: &[&str],
+) -> anyhow::Result<String> {
+    restic_run(config, via_wsl, restic_args, false)
+}
+
+/// Like restic_output, optionally printing restic's output as it runs
+fn restic_run(
+    config: &ResticConfig,
+    via_wsl: bool,
+    restic_args: &[&str],
+    show_output: bool,
+) -> anyhow::Result<String> {
+    let restic: &[&str] = if via_wsl { WSL_RESTIC } else { &["restic"] };
+
+    let wslenv = wslenv_for(config);
+    let mut env = restic_config_to_env(config);
+    if via_wsl {
+        env.push(("WSLENV", &wslenv));
+    }
+
+    let args = [restic, restic_args, &restic_repo_flags(config)].concat();
+    let mut cmd = sh(&args).env(&env);
+    if show_output {
+        cmd = cmd.show_output();
+    }
+    cmd.output()
+        .map_err(|e| restic_error::classify(e, &config.restic_repository))
+}
+
+/// Runs `restic init` if the repo doesn't exist yet. Returns whether it was freshly initialized.
+fn ensure_repo_initialized(config: &ResticConfig, via_wsl: bool) -> anyhow::Result<bool> {
+    let err = match restic_output(config, via_wsl, &["cat", "config"]) {
+        Ok(_) => return Ok(false),
+        Err(e) => e,
+    };
+    if !matches!(
+        err.downcast_ref(),
+        Some(restic_error::ResticError::NotInitialized { .. })
+    ) {
+        return Err(err);
+    }
+
+    restic_output(config, via_wsl, &["init"])?;
+    log::info!("Initialized new repo at {}", config.restic_repository);
+    Ok(true)
+}
+
+/// Initializes the repo before its first backup if it's configured with init_if_missing
+fn init_repo_if_missing(config: &ResticConfig, via_wsl: bool) -> anyhow::Result<()> {
+    // A dry run isn't supposed to touch the repos, so a missing one fails like without the flag
+    if config.init_if_missing
+        && !DRY_RUN.load(Ordering::Relaxed)
+        && ensure_repo_initialized(config, via_wsl)?
+    {
+        log::warn!(
+            "{} repo didn't exist, initialized it at {}",
+            config.name,
+            config.restic_repository
+        );
+    }
+    Ok(())
+}
+
+/// The repo's raw (compressed, deduplicated) size in bytes, roughly what the provider bills
+fn repo_raw_size(config: &ResticConfig, via_wsl: bool) -> anyhow::Result<u64> {
+    let stats = restic_output(config, via_wsl, &["stats", "--mode", "raw-data", "--json"])?;
+    let stats: serde_json::Value = serde_json::from_str(&stats)?;
+    stats["total_size"]
+        .as_u64()
+        .ok_or(anyhow!("No total_size in restic stats output"))
+}
+
+/// The repo's storage price in dollars per GB-month
+fn cost_rate(config: &ResticConfig) -> f64 {
+    config
+        .cost_per_gb_month
+        .unwrap_or(DEFAULT_COST_PER_GB_MONTH)
+}
+
+/// Logs a rough monthly storage cost for the repo from its raw size
+fn report_cost(config: &ResticConfig, via_wsl: bool) -> anyhow::Result<()> {
+    let gb = repo_raw_size(config, via_wsl)? as f64 / 1e9;
+    let rate = cost_rate(config);
+    log::info!(
+        "{} repo: {gb:.1} GB, approximately ${:.2}/month at ${rate}/GB-month",
+        config.name,
+        gb * rate
+    );
+    Ok(())
+}
+
+/// Runs `func`, and if it fails because of a lock older than STALE_LOCK_AGE, unlocks the repo and
+/// runs it once more. Younger locks are left alone since they're likely a backup still running.
+fn retry_if_stale_lock<F>(config: &ResticConfig, via_wsl: bool, mut func: F) -> anyhow::Result<()>
+where
+    F: FnMut() -> anyhow::Result<()>,
+{
+    let err = match func() {
+        Err(e) if UNLOCK_STALE_LOCKS => e,
+        result => return result,
+    };
+    let Some(restic_error::ResticError::Locked { lock, .. }) = err.downcast_ref() else {
+        return Err(err);
+    };
+    // restic prints the lock time in local time
+    let Some(age) = lock.created_at.and_then(|created_at| {
+        (chrono::Local::now().naive_local() - created_at)
+            .to_std()
+            .ok()
+    }) else {
+        return Err(err);
+    };
+    if age <= STALE_LOCK_AGE {
+        return Err(err);
+    }
+
+    log::warn!(
+        "Backup failed because {err}, {} ago. Assuming the lock is stale, unlocking and retrying",
+        pretty_duration(age)
+    );
+    if DRY_RUN.load(Ordering::Relaxed) {
+        log::info!("Dry run, would unlock {}", config.restic_repository);
+        return Err(err);
+    }
+    restic_output(config, via_wsl, &["unlock"])?;
+    func()
+}
+
+/// Logs the locks currently held on the repo, warning about ones old enough to be stale
+fn report_locks(config: &ResticConfig, via_wsl: bool) -> anyhow::Result<()> {
+    let lock_ids = restic_output(config, via_wsl, &["list", "locks", "--no-lock"])?;
+    let lock_ids: Vec<&str> = lock_ids
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect();
+    log::info!("{} has {} locks", config.restic_repository, lock_ids.len());
+
+    for id in lock_ids {
+        let lock = restic_output(config, via_wsl, &["cat", "lock", id, "--no-lock"])?;
+        let lock: serde_json::Value = serde_json::from_str(&lock)?;
+        let created_at =
+            chrono::DateTime::parse_from_rfc3339(lock["time"].as_str().unwrap_or_default())?;
+        let age = (chrono::Utc::now() - created_at.with_timezone(&chrono::Utc))
+            .to_std()
+            .unwrap_or_default();
+
+        let desc = format!(
+            "  lock {} held by PID {} on {}, {} old",
+            &id[..id.len().min(8)],
+            lock["pid"],
+            lock["hostname"].as_str().unwrap_or("unknown host"),
+            pretty_duration(age)
+        );
+        if age > STALE_LOCK_AGE {
+            log::warn!("{desc} (stale, `restic unlock` can remove it)");
+        } else {
+            log::info!("{desc}");
+        }
+    }
+    Ok(())
+}
+
+/// Logs the paths the latest snapshot with `tag` backed up that aren't configured anymore
+fn report_drift(config: &ResticConfig, tag: &str, configured: &[String]) -> anyhow::Result<()> {
+    let snapshots = restic_output(
+        config,
+        false,
+        &[
+            "snapshots",
+            "--latest",
+            "1",
+            "--tag",
+            tag,
+            "--json",
+            "--no-lock",
+        ],
+    )?;
+    let snapshots: serde_json::Value = serde_json::from_str(&snapshots)?;
+    let Some(snapshot) = snapshots.as_array().and_then(|s| s.last()) else {
+        log::info!("No {tag} snapshots in {}", config.restic_repository);
+        return Ok(());
+    };
+
+    let paths: Vec<&str> = snapshot["paths"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|p| p.as_str())
+        .collect();
+    let removed: Vec<&&str> = paths
+        .iter()
+        .filter(|path| !configured.iter().any(|dir| dir == *path))
+        .collect();
+    log::info!(
+        "Latest {tag} snapshot {} in {} has {} paths, {} no longer configured",
+        snapshot["short_id"].as_str().unwrap_or_default(),
+        config.restic_repository,
+        paths.len(),
+        removed.len()
+    );
+    for path in removed {
+        log::info!("  no longer configured: {path}");
+    }
+    Ok(())
+}
+
+// Set from --reset-repo-ids, when a repo was recreated on purpose
+static RESET_REPO_IDS: AtomicBool = AtomicBool::new(false);
+
+/// What the state files remember a repo under, e.g. "[work] Local repo (WSL)"
+fn repo_state_key(config: &ResticConfig, via_wsl: bool) -> String {
+    let wsl_suffix = if via_wsl { " (WSL)" } else { "" };
+    format!("{}{} repo{wsl_suffix}", task_name_prefix(), config.name)
+}
+
+/// Makes sure the repo at the URL is the same one we backed up to last time, so one that got
+/// replaced or re-initialized isn't quietly backed up to. The first run to a URL just remembers it,
+/// which includes each new URL of a repo templated with {year} or {month}.
+fn verify_repo_id(config: &ResticConfig, via_wsl: bool) -> anyhow::Result<()> {
+    // Backups to different repos can run at once, so keep their updates from clobbering
+    static REPO_IDS_LOCK: Mutex<()> = Mutex::new(());
+
+    let restic_config = restic_output(config, via_wsl, &["cat", "config", "--no-lock"])?;
+    let restic_config: serde_json::Value = serde_json::from_str(&restic_config)?;
+    let id = restic_config["id"]
+        .as_str()
+        .ok_or(anyhow!("No id in restic config"))?;
+
+    let _guard = REPO_IDS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let path = state_dir()?.join("repo-ids.json");
+    let mut ids: BTreeMap<String, String> = match fs::read_to_string(&path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse {}", path.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let url = &config.restic_repository;
+    match ids.get(url) {
+        Some(known) if known == id => return Ok(()),
+        Some(known) if !RESET_REPO_IDS.load(Ordering::Relaxed) => {
+            return Err(anyhow!(
+                "{} at {url} has id {id}, but it was {known} last time! \
+                If that's intended, run with --reset-repo-ids",
+                repo_state_key(config, via_wsl)
+            ));
+        }
+        Some(_) => log::warn!("Resetting remembered id of {url} to {id}"),
+        None => log::info!("Remembering id {id} for {url}"),
+    }
+    if DRY_RUN.load(Ordering::Relaxed) {
+        log::info!("Dry run, not saving the id");
+        return Ok(());
+    }
+    ids.insert(url.clone(), id.to_owned());
+    fs::create_dir_all(state_dir()?)?;
+    write_private_file(&path, &serde_json::to_string_pretty(&ids)?)
+}
+
+// Set from --check-quota
+static CHECK_QUOTA: AtomicBool = AtomicBool::new(false);
+
+/// Fails if the repo's estimated size is too close to its configured storage cap to start a backup.
+/// The provider isn't asked, so the bucket's real usage can differ.
+fn check_storage_cap(config: &ResticConfig, via_wsl: bool) -> anyhow::Result<()> {
+    if !CHECK_QUOTA.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let Some(cap_gb) = config.storage_cap_gb else {
+        log::info!("No storage cap for {}, not checking quota", config.name);
+        return Ok(());
+    };
+
+    let used_gb = repo_raw_size(config, via_wsl)? as f64 / 1e9;
+    let free_gb = cap_gb - used_gb;
+    log::info!(
+        "{} repo has about {free_gb:.1} GB left of its {cap_gb} GB cap, going by restic's stats",
+        config.name
+    );
+    if free_gb < MIN_FREE_QUOTA_GB {
+        return Err(anyhow!(
+            "{} repo only has about {free_gb:.1} GB left of its {cap_gb} GB cap, not backing up",
+            config.name
+        ));
+    }
+    Ok(())
+}
+
+/// Logs the repo's snapshots, oldest first, with notes from --note called out
+fn report_snapshots(config: &ResticConfig, via_wsl: bool) -> anyhow::Result<()> {
+    let snapshots = restic_output(config, via_wsl, &["snapshots", "--json", "--no-lock"])?;
+    let snapshots: Vec<serde_json::Value> = serde_json::from_str(&snapshots)?;
+    log::info!(
+        "{} has {} snapshots",
+        config.restic_repository,
+        snapshots.len()
+    );
+
+    for snapshot in &snapshots {
+        let tags: Vec<&str> = snapshot["tags"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|t| t.as_str())
+            .collect();
+        let (notes, tags): (Vec<&str>, Vec<&str>) =
+            tags.iter().partition(|t| t.starts_with(NOTE_TAG_PREFIX));
+        let time = snapshot["time"].as_str().unwrap_or_default();
+        let desc = format!(
+            "  {} {} on {} [{}]",
+            snapshot["short_id"].as_str().unwrap_or_default(),
+            time.get(..19).unwrap_or(time),
+            snapshot["hostname"].as_str().unwrap_or_default(),
+            tags.join(", ")
+        );
+        if notes.is_empty() {
+            log::info!("{desc}");
+        } else {
+            let notes: Vec<&str> = notes.iter().map(|n| &n[NOTE_TAG_PREFIX.len()..]).collect();
+            log::info!("{desc} NOTE: {}", notes.join(", "));
+        }
+    }
+    Ok(())
+}
+
+/// The repo's snapshots keyed by host, tags and day, each with a description for logging.
+/// Snapshot IDs differ between repos even for the same backup run, so the key has to do instead.
+fn snapshot_days(config: &ResticConfig, via_wsl: bool) -> anyhow::Result<BTreeMap<String, String>> {
+    let snapshots = restic_output(config, via_wsl, &["snapshots", "--json", "--no-lock"])?;
+    let snapshots: Vec<serde_json::Value> = serde_json::from_str(&snapshots)?;
+
+    let mut days = BTreeMap::new();
+    for snapshot in &snapshots {
+        // Split backups get a run-<time> tag that won't match between repos
+        let mut tags: Vec<&str> = snapshot["tags"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|t| t.as_str())
+            .filter(|t| !t.starts_with("run-"))
+            .collect();
+        tags.sort_unstable();
+        tags.dedup();
+        let time = snapshot["time"].as_str().unwrap_or_default();
+        let host = snapshot["hostname"].as_str().unwrap_or_default();
+        let key = format!(
+            "{} on {host} [{}]",
+            time.get(..10).unwrap_or(time),
+            tags.join(", ")
+        );
+        let desc = format!(
+            "{key}, e.g. {} at {}",
+            snapshot["short_id"].as_str().unwrap_or_default(),
+            time.get(..19).unwrap_or(time),
+        );
+        days.entry(key).or_insert(desc);
+    }
+    Ok(days)
+}
+
+/// Logs the snapshots that one repo has for a day and the other doesn't. Fails if there are any.
+fn compare_snapshots(
+    (config_a, wsl_a): &(ResticConfig, bool),
+    (config_b, wsl_b): &(ResticConfig, bool),
+) -> anyhow::Result<()> {
+    let days_a = snapshot_days(config_a, *wsl_a)?;
+    let days_b = snapshot_days(config_b, *wsl_b)?;
+
+    let mut missing = 0;
+    for (days, other, name, other_name) in [
+        (&days_a, &days_b, &config_a.name, &config_b.name),
+        (&days_b, &days_a, &config_b.name, &config_a.name),
+    ] {
+        for (key, desc) in days {
+            if !other.contains_key(key) {
+                log::warn!("Only in {name}, not {other_name}: {desc}");
+                missing += 1;
+            }
+        }
+    }
+    log::info!(
+        "{} has {} snapshot days, {} has {}",
+        config_a.name,
+        days_a.len(),
+        config_b.name,
+        days_b.len()
+    );
+    if missing > 0 {
+        return Err(anyhow!(
+            "{missing} snapshot days are only in one of the repos"
+        ));
+    }
+    Ok(())
+}
+
+/// The tags of the snapshots this OS makes, for commands that shouldn't touch other machines'
+fn os_snapshot_tags(args: &Args) -> &'static [&'static str] {
+    match args.os {
+        Os::Windows => &["Windows", "WSL"],
+        Os::Mac => &["macOS"],
+        Os::Linux => &["Linux"],
+    }
+}
+
+/// `restic forget` flags for the repo's keep policy, limited to snapshots with one of the tags
+fn forget_flags(config: &ResticConfig, tags: &[&str]) -> Vec<String> {
+    let keep = &config.keep;
+    let mut flags: Vec<String> = vec![
+        "--keep-daily".into(),
+        keep.daily.to_string(),
+        "--keep-weekly".into(),
+        keep.weekly.to_string(),
+        "--keep-monthly".into(),
+        keep.monthly.to_string(),
+    ];
+    for tag in tags {
+        flags.extend(["--tag".into(), tag.to_string()]);
+    }
+    flags
+}
+
+/// `restic forget` flags for the partial snapshots of --changed-since runs. Each has its own paths,
+/// so with the default grouping by paths none would ever be forgotten. They're grouped by tags
+/// instead, and only kept as dailies since they just fill in between full snapshots.
+fn changed_since_forget_flags(config: &ResticConfig, tags: &[&str]) -> Vec<String> {
+    let mut flags: Vec<String> = vec![
+        "--group-by".into(),
+        "host,tags".into(),
+        "--keep-daily".into(),
+        config.keep.daily.to_string(),
+    ];
+    for tag in tags {
+        // A comma means the snapshot needs both tags
+        flags.extend(["--tag".into(), format!("{tag},{CHANGED_SINCE_TAG}")]);
+    }
+    flags
+}
+
+/// Logs the snapshots the keep policy would forget, without forgetting them
+fn preview_forget(config: &ResticConfig, via_wsl: bool, tags: &[&str]) -> anyhow::Result<()> {
+    // Partial snapshots are kept by the first forget and can be removed by the second
+    let mut kept = BTreeSet::new();
+    let mut removed = BTreeSet::new();
+    for flags in [
+        forget_flags(config, tags),
+        changed_since_forget_flags(config, tags),
+    ] {
+        let mut args = vec!["forget", "--dry-run", "--json"];
+        args.extend(flags.iter().map(String::as_str));
+        let groups = restic_output(config, via_wsl, &args)?;
+        let groups: Vec<serde_json::Value> = serde_json::from_str(&groups)?;
+
+        for group in &groups {
+            for snapshot in group["keep"].as_array().into_iter().flatten() {
+                kept.insert(snapshot["id"].as_str().unwrap_or_default().to_owned());
+            }
+            for snapshot in group["remove"].as_array().into_iter().flatten() {
+                if !removed.insert(snapshot["id"].as_str().unwrap_or_default().to_owned()) {
+                    continue;
+                }
+                let time = snapshot["time"].as_str().unwrap_or_default();
+                log::info!(
+                    "  would forget {} from {} on {}",
+                    snapshot["short_id"].as_str().unwrap_or_default(),
+                    time.get(..19).unwrap_or(time),
+                    snapshot["hostname"].as_str().unwrap_or_default()
+                );
+            }
+        }
+    }
+    log::info!(
+        "Forgetting in {} would keep {} and remove {} snapshots",
+        config.restic_repository,
+        kept.difference(&removed).count(),
+        removed.len()
+    );
+    Ok(())
+}
+
+/// Forgets the snapshots with one of the tags that the keep policy doesn't keep, and deletes the
+/// data only they used
+fn prune_repository(config: &ResticConfig, via_wsl: bool, tags: &[&str]) -> anyhow::Result<()> {
+    // The partial snapshots go first, so the prune after the main forget covers their data too
+    let flags = changed_since_forget_flags(config, tags);
+    let mut args = vec!["forget"];
+    args.extend(flags.iter().map(String::as_str));
+    args.extend(dry_run_flag());
+    let partial_output = restic_output(config, via_wsl, &args)?;
+
+    let flags = forget_flags(config, tags);
+    let mut args = vec!["forget", "--prune"];
+    args.extend(flags.iter().map(String::as_str));
+    args.extend(dry_run_flag());
+    let output = restic_output(config, via_wsl, &args)?;
+    if DRY_RUN.load(Ordering::Relaxed) {
+        log::info!(
+            "Dry run, {} would forget:\n{partial_output}{output}",
+            config.restic_repository
+        );
+        return Ok(());
+    }
+    log::info!("Pruned {}", config.restic_repository);
+    Ok(())
+}
+
+/// Prunes after a backup, except after a --changed-since one. Its snapshots are partial, and
+/// forgetting by the keep policy can wait for a full one.
+fn prune_after_backup(
+    name: &str,
+    config: &ResticConfig,
+    via_wsl: bool,
+    tags: &[&str],
+    results: &mut Vec<TaskResult>,
+) {
+    if changed_since().is_some() {
+        skip_task(name, "changed-since run", results);
+        return;
+    }
+    try_task(name, || prune_repository(config, via_wsl, tags), results);
+}
+
+/// Whether the task just run succeeded, counting one that only warned, like a backup that saved
+/// its snapshot without some unreadable files, and one skipped for having succeeded last run.
+/// Pruning after a failed backup could leave the repo in a state we don't expect.
+fn last_task_succeeded(results: &[TaskResult]) -> bool {
+    results.last().is_some_and(|r| match &r.status {
+        TaskStatus::Succeeded | TaskStatus::Warned(_) => true,
+        TaskStatus::Skipped(reason) => reason == COMPLETED_SKIP_REASON,
+        _ => false,
+    })
+}
+
+fn last_checks_path() -> anyhow::Result<PathBuf> {
+    Ok(state_dir()?.join("last-checks.json"))
+}
+
+/// When each repo last passed `restic check`, keyed like the repo ids
+fn load_last_checks() -> anyhow::Result<BTreeMap<String, String>> {
+    let path = last_checks_path()?;
+    match fs::read_to_string(&path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// `restic check` flags for the repo's read-back sample
+fn check_flags(config: &ResticConfig) -> Vec<String> {
+    config
+        .check_read_data_percent
+        .map(|percent| format!("--read-data-subset={percent}%"))
+        .into_iter()
+        .collect()
+}
+
+/// Verifies the repo's integrity with `restic check`, reading back a sample of the data if
+/// configured, and remembers when it passed
+fn check_repository(config: &ResticConfig, via_wsl: bool) -> anyhow::Result<()> {
+    // Checks of different repos can finish at once
+    static LAST_CHECKS_LOCK: Mutex<()> = Mutex::new(());
+
+    let flags = check_flags(config);
+    let mut args = vec!["check"];
+    args.extend(flags.iter().map(String::as_str));
+    restic_run(config, via_wsl, &args, true)?;
+    log::info!("Checked {}", config.restic_repository);
+
+    let _guard = LAST_CHECKS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut last_checks = load_last_checks()?;
+    last_checks.insert(
+        repo_state_key(config, via_wsl),
+        chrono::Utc::now().to_rfc3339(),
+    );
+    fs::create_dir_all(state_dir()?)?;
+    write_private_file(
+        &last_checks_path()?,
+        &serde_json::to_string_pretty(&last_checks)?,
+    )
+}
+
+/// Runs check_repository as its own task if the repo is configured to be checked and it's been
+/// long enough since the last time
+fn check_repository_if_due(config: &ResticConfig, via_wsl: bool, results: &mut Vec<TaskResult>) {
+    let Some(interval) = config.check_interval else {
+        return;
+    };
+    if !last_task_succeeded(results) || DRY_RUN.load(Ordering::Relaxed) {
+        return;
+    }
+    let key = repo_state_key(config, via_wsl);
+    match load_last_checks() {
+        Ok(last_checks) => {
+            let since = last_checks
+                .get(&key)
+                .and_then(|last| chrono::DateTime::parse_from_rfc3339(last).ok())
+                .and_then(|last| (chrono::Utc::now() - last.to_utc()).to_std().ok());
+            if let Some(since) = since.filter(|since| *since < interval) {
+                log::info!(
+                    "Not checking {key}, last checked {} ago",
+                    pretty_duration(since)
+                );
+                return;
+            }
+        }
+        Err(e) => log::warn!("Failed to load last repo checks, checking anyway: {e}"),
+    }
+    try_task(
+        &repo_task_name("Check", config, via_wsl),
+        || check_repository(config, via_wsl),
+        results,
+    );
+}
+
+fn is_scheduled_today(config: &ResticConfig) -> bool {
+    config.weekdays.is_empty() || config.weekdays.contains(&chrono::Local::now().weekday())
+}
+
+fn backup_windows_to(
+    backup_dirs: &[String],
+    wsl_paths: &anyhow::Result<Vec<String>>,
+    windows_config: &ResticConfig,
+    wsl_config: &ResticConfig,
+    results: &mut Vec<TaskResult>,
+) {
+    let windows_task = format!("Backup Windows Filesystem ({})", windows_config.name);
+    let wsl_task = format!("Backup WSL ({})", wsl_config.name);
+    if !is_scheduled_today(windows_config) {
+        skip_task(&windows_task, "not scheduled today", results);
+        skip_task(&wsl_task, "not scheduled today", results);
+        return;
+    }
+
+    if nothing_changed(backup_dirs) {
+        skip_task(&windows_task, "no files changed", results);
+    } else {
+        try_task(
+            &windows_task,
+            || {
+                init_repo_if_missing(windows_config, false)?;
+                verify_repo_id(windows_config, false)?;
+                check_storage_cap(windows_config, false)?;
+                retry_if_stale_lock(windows_config, false, || {
+                    backup_filesystem_to(
+                        backup_dirs,
+                        windows_config,
+                        &[&["--tag", "Windows", "--use-fs-snapshot"], dry_run_flag()].concat(),
+                    )
+                })
+            },
+            results,
+        );
+    }
+    let windows_succeeded = last_task_succeeded(results);
+    if wsl_paths.as_ref().is_ok_and(|paths| nothing_changed(paths)) {
+        skip_task(&wsl_task, "no files changed", results);
+    } else {
+        try_task(
+            &wsl_task,
+            || {
+                let paths = wsl_paths
+                    .as_ref()
+                    .map_err(|e| anyhow!("Failed to find changed WSL files: {e:#}"))?;
+                if let Some(command) = wsl_config.password_command.as_ref() {
+                    check_password_command(command, true)?;
+                }
+                init_repo_if_missing(wsl_config, true)?;
+                verify_repo_id(wsl_config, true)?;
+                check_storage_cap(wsl_config, true)?;
+                retry_if_stale_lock(wsl_config, true, || backup_wsl(wsl_config, paths))
+            },
+            results,
+        );
+    }
+    let wsl_succeeded = last_task_succeeded(results);
+
+    // Both backups can go to the same repo, which only needs pruning once
+    if windows_config.restic_repository == wsl_config.restic_repository {
+        if windows_succeeded && wsl_succeeded {
+            prune_after_backup(
+                &format!("Prune Windows and WSL Snapshots ({})", windows_config.name),
+                windows_config,
+                false,
+                &["Windows", "WSL"],
+                results,
+            );
+            check_repository_if_due(windows_config, false, results);
+        }
+        return;
+    }
+    if windows_succeeded {
+        prune_after_backup(
+            &format!("Prune Windows Snapshots ({})", windows_config.name),
+            windows_config,
+            false,
+            &["Windows"],
+            results,
+        );
+        check_repository_if_due(windows_config, false, results);
+    }
+    if wsl_succeeded {
+        prune_after_backup(
+            &format!("Prune WSL Snapshots ({})", wsl_config.name),
+            wsl_config,
+            true,
+            &["WSL"],
+            results,
+        );
+        check_repository_if_due(wsl_config, true, results);
+    }
+}
+
+/// Runs each concurrency group on its own thread, with the items within a group in order
+fn run_in_concurrency_groups<T, G, F>(
+    items: &[T],
+    group_of: G,
+    run: F,
+    results: &mut Vec<TaskResult>,
+) where
+    T: Sync,
+    G: Fn(&T) -> &str,
+    F: Fn(&T, &mut Vec<TaskResult>) + Sync,
+{
+    let mut groups: Vec<(&str, Vec<&T>)> = Vec::new();
+    for item in items {
+        let group = group_of(item);
+        match groups.iter_mut().find(|(g, _)| *g == group) {
+            Some((_, members)) => members.push(item),
+            None => groups.push((group, vec![item])),
+        }
+    }
+
+    let group_results: Vec<Vec<TaskResult>> = thread::scope(|scope| {
+        let handles: Vec<_> = groups
+            .iter()
+            .map(|(_, members)| {
+                let run = &run;
+                scope.spawn(move || {
+                    let mut results = Vec::new();
+                    for item in members {
+                        run(item, &mut results);
+                    }
+                    results
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle.join().unwrap_or_else(|_| {
+                    vec![TaskResult {
+                        name: "Backup Thread".into(),
+                        duration: Duration::ZERO,
+                        status: TaskStatus::Failed("Panicked".into()),
+                    }]
+                })
+            })
+            .collect()
+    });
+    results.extend(group_results.into_iter().flatten());
+}
+
+fn windows_local_configs(cloud_config: &ResticConfig) -> (ResticConfig, ResticConfig) {
+    // The local repo as reached from Windows or from WSL, which differ only in the path
+    let local_config = |restic_repository: &str| ResticConfig {
+        name: "Local".into(),
+        restic_repository: restic_repository.into(),
+        restic_password: cloud_config.restic_password.clone(),
+        password_command: cloud_config.password_command.clone(),
+        aws_access_key_id: None,
+        aws_secret_access_key: None,
+        // These are about the filesystem being backed up, which is the same whatever the repo
+        ignore_inode: cloud_config.ignore_inode,
+        ignore_ctime: cloud_config.ignore_ctime,
+        cost_per_gb_month: Some(0.0),
+        profile: Some(PerfProfile::FastLocal),
+        exclude_tier: ExcludeTier::Minimal,
+        local: true,
+        check_interval: Some(LOCAL_CHECK_INTERVAL),
+        check_read_data_percent: Some(LOCAL_CHECK_READ_DATA_PERCENT),
+        ..Default::default()
+    };
+    (local_config("Z:\\restic"), local_config("/mnt/c/restic"))
+}
+
+fn do_backup_windows(
+    backup_dirs: &[String],
+    repo_names: &[&str],
+    cloud_config: &ResticConfig,
+    results: &mut Vec<TaskResult>,
+) {
+    let (windows_to_local_config, wsl_to_local_config) = windows_local_configs(cloud_config);
+    let mut destinations: Vec<(&ResticConfig, &ResticConfig)> = repo_names
+        .iter()
+        .filter_map(|name| match *name {
+            "Local" => Some((&windows_to_local_config, &wsl_to_local_config)),
+            "Cloud" => Some((cloud_config, cloud_config)),
+            // Already rejected by check_task_specs
+            _ => None,
+        })
+        .collect();
+    destinations.sort_by_key(|(windows_config, _)| windows_config.order);
+    // Shared by the destinations, since finding changed files walks the whole WSL home dir
+    let wsl_paths = wsl_backup_paths();
+
+    // Remote repos in different groups upload at the same time and share the upload budget
+    let mut remote_groups: Vec<&str> = destinations
+        .iter()
+        .filter(|(windows_config, _)| !windows_config.local)
+        .map(|(windows_config, _)| windows_config.concurrency_group.as_str())
+        .collect();
+    remote_groups.sort();
+    remote_groups.dedup();
+    CONCURRENT_REPOS.store(remote_groups.len().max(1), Ordering::Relaxed);
+    run_in_concurrency_groups(
+        &destinations,
+        |(windows_config, _)| &windows_config.concurrency_group,
+        |(windows_config, wsl_config), results| {
+            backup_windows_to(backup_dirs, &wsl_paths, windows_config, wsl_config, results)
+        },
+        results,
+    );
+}
+
+/// Backs up macOS or Linux, which work the same apart from the snapshot tag
+fn do_backup_unix(
+    os: Os,
+    backup_dirs: &[String],
+    repo_names: &[&str],
+    cloud_config: &ResticConfig,
+    results: &mut Vec<TaskResult>,
+) {
+    // The cloud repo is the only one macOS and Linux back up to
+    if !repo_names.contains(&"Cloud") {
+        return;
+    }
+    let tag = os.pretty();
+    let backup_task = format!("Backup {tag} Filesystem");
+    if !is_scheduled_today(cloud_config) {
+        skip_task(&backup_task, "not scheduled today", results);
+        return;
+    }
+    if nothing_changed(backup_dirs) {
+        skip_task(&backup_task, "no files changed", results);
+        return;
+    }
+    try_task(
+        &backup_task,
+        || {
+            init_repo_if_missing(cloud_config, false)?;
+            verify_repo_id(cloud_config, false)?;
+            check_storage_cap(cloud_config, false)?;
+            retry_if_stale_lock(cloud_config, false, || {
+                backup_filesystem_to(
+                    backup_dirs,
+                    cloud_config,
+                    &[&["--tag", tag], dry_run_flag()].concat(),
+                )
+            })
+        },
+        results,
+    );
+    if last_task_succeeded(results) {
+        prune_after_backup(
+            &format!("Prune {tag} Snapshots"),
+            cloud_config,
+            false,
+            &[tag],
+            results,
+        );
+        check_repository_if_due(cloud_config, false, results);
+    }
+}
+
+/// Substitutes `{year}`, `{month}` and `{hostname}` in a repo URL, e.g. for monthly repos
+fn expand_repo_template(template: &str) -> anyhow::Result<String> {
+    let now = chrono::Local::now();
+
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = start
+            + rest[start..]
+                .find('}')
+                .ok_or(anyhow!("Unclosed '{{' in repo URL: {template}"))?;
+        let value = match &rest[start + 1..end] {
+            "year" => now.format("%Y").to_string(),
+            "month" => now.format("%m").to_string(),
+            "hostname" => hostname::get()?
+                .into_string()
+                .map_err(|_| anyhow!("Hostname is not valid UTF-8"))?,
+            var => {
+                return Err(anyhow!(
+                    "Unknown variable {{{var}}} in repo URL: {template}"
+                ))
+            }
+        };
+        expanded.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+
+    if expanded.is_empty() || expanded.contains('}') || expanded.contains(char::is_whitespace) {
+        return Err(anyhow!("Malformed repo URL after expansion: {expanded}"));
+    }
+    Ok(expanded)
+}
+
+fn check_clock_skew(url: &str) -> anyhow::Result<()> {
+    // A slow server fails the check, which only warns, rather than holding up the backup
+    let headers = sh(&["curl", "--silent", "--head", url])
+        .timeout(Duration::from_secs(30))
+        .output()?;
+    let date = headers
+        .lines()
+        .find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case("date").then(|| value.trim())
+        })
+        .ok_or(anyhow!("No Date header from {url}"))?;
+
+    let server_time = chrono::DateTime::parse_from_rfc2822(date)?;
+    let skew_secs = (chrono::Utc::now() - server_time.with_timezone(&chrono::Utc))
+        .num_seconds()
+        .unsigned_abs();
+    if skew_secs > MAX_CLOCK_SKEW.as_secs() {
+        log::warn!(
+            "Local clock is off by {} compared to {url}, snapshots will be misdated",
+            pretty_duration(Duration::from_secs(skew_secs))
+        );
+    }
+    Ok(())
+}
+
+/// Pings a healthchecks.io-style URL, where `suffix` is "/start", "" for success or "/fail".
+/// Only logs failures, since the monitoring being down isn't the backup's problem.
+fn ping_healthcheck(url: &str, suffix: &str, body: &str) {
+    let url = format!("{}{suffix}", url.trim_end_matches('/'));
+    let result = sh(&[
+        "curl",
+        "--silent",
+        "--show-error",
+        "--fail",
+        "--retry",
+        "3",
+        "--data-binary",
+        "@-",
+        &url,
+    ])
+    .input(body)
+    .timeout(Duration::from_secs(60))
+    .run();
+    if let Err(e) = result {
+        log::warn!("Failed to ping healthcheck {url}: {e}");
+    }
+}
+
+/// Whether `program` is a path to a file or can be found on PATH
+fn program_exists(program: &str) -> bool {
+    if program.contains(['/', '\\']) {
+        return Path::new(program).is_file();
+    }
+    let extensions: &[&str] = if cfg!(windows) { &["", ".exe"] } else { &[""] };
+    env::var_os("PATH").is_some_and(|path| {
+        env::split_paths(&path).any(|dir| {
+            extensions
+                .iter()
+                .any(|ext| dir.join(format!("{program}{ext}")).is_file())
+        })
+    })
+}
+
+/// Checks that a password command's program exists where restic will run it, which for the
+/// repos reached through WSL means WSL's PATH rather than ours
+fn check_password_command(command: &str, via_wsl: bool) -> anyhow::Result<()> {
+    let program = command.split_whitespace().next().unwrap_or_default();
+    if via_wsl {
+        // Without a shell, like restic itself is run, so this sees the PATH restic will
+        let found = sh(&["wsl.exe", "--shell-type", "none", "which", program]).run();
+        return found.map_err(|_| anyhow!("Password command not found in WSL: {program}"));
+    }
+    if !program_exists(program) {
+        return Err(anyhow!("Password command not found: {program}"));
+    }
+    Ok(())
+}
+
+fn keep_count(var: &str, default: u32) -> anyhow::Result<u32> {
+    match profile_env_var(var) {
+        Ok(count) => count
+            .parse()
+            .with_context(|| format!("{var} is not a number: {count}")),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Parses an optional setting from profile_env_var, naming the var if it's malformed
+fn parse_env_var<T>(var: &str) -> anyhow::Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: fmt::Display,
+{
+    match profile_env_var(var) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow!("Invalid {var} {value:?}: {e}")),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Reads a comma-separated list of days like "mon,wed" or "saturday, sunday", empty if unset
+fn parse_weekdays(var: &str) -> anyhow::Result<Vec<chrono::Weekday>> {
+    let Ok(days) = profile_env_var(var) else {
+        return Ok(Vec::new());
+    };
+    days.split(',')
+        .map(str::trim)
+        .filter(|day| !day.is_empty())
+        .map(|day| {
+            day.parse()
+                .map_err(|_| anyhow!("Unknown day in {var}: {day}"))
+        })
+        .collect()
+}
+
+/// Checks a duration is in the Go format restic parses, e.g. "30m" or "1h30m"
+fn validate_go_duration(duration: &str) -> anyhow::Result<()> {
+    let invalid = || anyhow!("Invalid duration {duration:?}, expected something like 30m or 1h30m");
+    let mut rest = duration;
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    // Each part is a number and a unit
+    while !rest.is_empty() {
+        let unit_start = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let unit_end = rest[unit_start..]
+            .find(|c: char| c.is_ascii_digit())
+            .map_or(rest.len(), |i| unit_start + i);
+        if unit_start == 0 || !["h", "m", "s", "ms"].contains(&&rest[unit_start..unit_end]) {
+            return Err(invalid());
+        }
+        rest = &rest[unit_end..];
+    }
+    Ok(())
+}
+
+fn get_cloud_config() -> anyhow::Result<ResticConfig> {
+    // A password command takes the place of the password, like with restic itself
+    let password_command = profile_env_var("BACKUPER_RESTIC_PASSWORD_COMMAND").ok();
+    if let Some(command) = password_command.as_ref() {
+        check_password_command(command, false)?;
+    }
+    let restic_password = match password_command {
+        Some(_) => String::new(),
+        None => profile_env_var("BACKUPER_RESTIC_PASSWORD")?,
+    };
+
+    let default_keep = KeepPolicy::default();
+    let config = ResticConfig {
+        name: "Cloud".into(),
+        restic_repository: expand_repo_template(&profile_env_var("BACKUPER_RESTIC_REPOSITORY")?)?,
+        restic_password,
+        password_command,
+        aws_access_key_id: Some(profile_env_var("BACKUPER_AWS_ACCESS_KEY_ID")?),
+        aws_secret_access_key: Some(profile_env_var("BACKUPER_AWS_SECRET_ACCESS_KEY")?),
+        ignore_inode: parse_env_var("BACKUPER_IGNORE_INODE")?.unwrap_or_default(),
+        ignore_ctime: parse_env_var("BACKUPER_IGNORE_CTIME")?.unwrap_or_default(),
+        retry_lock: profile_env_var("BACKUPER_RETRY_LOCK").ok(),
+        order: parse_env_var("BACKUPER_ORDER")?.unwrap_or_default(),
+        // The local repos are in the default "" group
+        concurrency_group: profile_env_var("BACKUPER_CONCURRENCY_GROUP").unwrap_or_default(),
+        weekdays: parse_weekdays("BACKUPER_WEEKDAYS")?,
+        cost_per_gb_month: parse_env_var("BACKUPER_COST_PER_GB_MONTH")?,
+        tuning: env_tuning()?,
+        profile: parse_env_var("BACKUPER_RESTIC_PROFILE")?,
+        parallel_backups: parse_env_var("BACKUPER_PARALLEL_BACKUPS")?.unwrap_or_default(),
+        limit_upload_kib: parse_env_var("BACKUPER_LIMIT_UPLOAD_KIB")?,
+        storage_cap_gb: parse_env_var("BACKUPER_STORAGE_CAP_GB")?,
+        keep: KeepPolicy {
+            daily: keep_count("BACKUPER_KEEP_DAILY", default_keep.daily)?,
+            weekly: keep_count("BACKUPER_KEEP_WEEKLY", default_keep.weekly)?,
+            monthly: keep_count("BACKUPER_KEEP_MONTHLY", default_keep.monthly)?,
+        },
+        exclude_tier: parse_env_var("BACKUPER_EXCLUDE_TIER")?.unwrap_or_default(),
+        check_interval: parse_env_var::<u64>("BACKUPER_CHECK_INTERVAL_DAYS")?
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+        check_read_data_percent: parse_env_var("BACKUPER_CHECK_READ_DATA_PERCENT")?,
+        init_if_missing: profile_env_var("BACKUPER_INIT_IF_MISSING")
+            .ok()
+            .map(|init| init.parse())
+            .transpose()
+            .context("BACKUPER_INIT_IF_MISSING must be true or false")?
+            .unwrap_or_default(),
+        // Comma-separated names from ENV_PROFILES
+        env_profiles: profile_env_var("BACKUPER_RESTIC_ENV_PROFILES")
+            .map(|p| {
+                p.split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        ..Default::default()
+    };
+    if let Some(retry_lock) = &config.retry_lock {
+        validate_go_duration(retry_lock).context("BACKUPER_RETRY_LOCK")?;
+    }
+    if let Some(percent) = config.check_read_data_percent {
+        // Written so NaN fails too
+        if !(percent > 0.0 && percent <= 100.0) {
+            return Err(anyhow!(
+                "BACKUPER_CHECK_READ_DATA_PERCENT must be more than 0 and at most 100, not {percent}"
+            ));
+        }
+    }
+    validate_env_profiles(&config)?;
+    Ok(config)
+}
+
+/// The repos `TaskSpec::Backup` can name on this OS
+fn backup_repo_names(args: &Args) -> &'static [&'static str] {
+    if args.os == Os::Windows {
+        &["Local", "Cloud"]
+    } else {
+        &["Cloud"]
+    }
+}
+
+/// Catches typos in the task list before any task runs
+fn check_task_specs(specs: &[TaskSpec], args: &Args) -> anyhow::Result<()> {
+    for spec in specs {
+        if let TaskSpec::Backup(name) = spec {
+            if !backup_repo_names(args).contains(name) {
+                return Err(anyhow!("Unknown repo in task list: {name}"));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_task_specs(
+    specs: &[TaskSpec],
+    args: &Args,
+    backup_dirs: &[String],
+    cloud_config: &ResticConfig,
+    results: &mut Vec<TaskResult>,
+) {
+    let mut remaining = specs;
+    while let Some((spec, rest)) = remaining.split_first() {
+        match spec {
+            TaskSpec::Upgrade => {
+                let name = format!("{} Upgrades", args.os.pretty());
+                let upgrade = match args.os {
+                    Os::Windows => do_windows_upgrades,
+                    Os::Mac => do_macos_upgrades,
+                    Os::Linux => do_linux_upgrades,
+                };
+                if args.dry_run {
+                    skip_task(&name, "dry run", results);
+                } else {
+                    try_task(&name, upgrade, results);
+                }
+            }
+            TaskSpec::Hook { name, .. } if args.dry_run => skip_task(name, "dry run", results),
+            TaskSpec::Hook { name, cmd } => try_task(name, || sh(cmd).run(), results),
+            TaskSpec::Backup(_) => {
+                let batch_len = remaining
+                    .iter()
+                    .take_while(|s| matches!(s, TaskSpec::Backup(_)))
+                    .count();
+                let repo_names: Vec<&str> = remaining[..batch_len]
+                    .iter()
+                    .filter_map(|s| match s {
+                        TaskSpec::Backup(name) => Some(*name),
+                        _ => None,
+                    })
+                    .collect();
+                if args.os == Os::Windows {
+                    do_backup_windows(backup_dirs, &repo_names, cloud_config, results);
+                } else {
+                    do_backup_unix(args.os, backup_dirs, &repo_names, cloud_config, results);
+                }
+                remaining = &remaining[batch_len..];
+                continue;
+            }
+        }
+        remaining = rest;
+    }
+}
 
-    let input = backup_dirs_to_strings(file_patterns)?.join("\n");
-    let env = restic_config_to_env(config);
-    sh(&restic_args).env(&env).input(&input).run()?;
+fn do_backup(args: &Args, results: &mut Vec<TaskResult>) -> anyhow::Result<()> {
+    let cloud_config = get_cloud_config()?;
+    let task_specs = match args.os {
+        Os::Windows => WINDOWS_TASKS,
+        Os::Mac => MAC_TASKS,
+        Os::Linux => LINUX_TASKS,
+    };
+    check_task_specs(task_specs, args)?;
+
+    if args.maintenance {
+        return do_maintenance(args, task_specs, results);
+    }
+
+    if let Err(e) = remove_stale_temp_files() {
+        log::warn!("Failed to clean up stale temp files: {e}");
+    }
+
+    let backup_dirs = resolve_backup_dirs(&configured_backup_dirs(args), args)?;
+
+    if IMPORT_TIME_MACHINE_EXCLUDES && args.os == Os::Mac {
+        let excludes = load_time_machine_excludes(&backup_dirs)
+            .context("Failed to import Time Machine exclusions")?;
+        let _ = TIME_MACHINE_EXCLUDES.set(excludes);
+    }
+    if args.file_counts {
+        log_file_counts(&backup_dirs);
+    }
+    if args.dry_run {
+        log::info!("Dry run, would back up:\n{}", backup_dirs.join("\n"));
+    }
+    let backup_dirs = match args.changed_since {
+        Some(since) => {
+            // Only the excludes every repo shares, restic still applies each repo's own
+            let shared_excludes = all_exclude_patterns(&ResticConfig {
+                exclude_tier: ExcludeTier::Minimal,
+                ..Default::default()
+            })?;
+            let files = changed_files(&backup_dirs, since, &shared_excludes);
+            log::info!("{} files changed since {since}", files.len());
+            check_path_limit(&files, args)?;
+            files
+        }
+        None => backup_dirs,
+    };
+    if let Some(url) = CLOCK_CHECK_URL {
+        if let Err(e) = check_clock_skew(url) {
+            log::warn!("Failed to check clock skew: {e}");
+        }
+    }
 
-    log::info!("Backed up local filesystem to {}", config.restic_repository);
+    run_task_specs(task_specs, args, &backup_dirs, &cloud_config, results);
     Ok(())
 }
 
-fn backup_wsl(config: &ResticConfig) -> anyhow::Result<()> {
-    // In case I forgot to kill `restic mount`, don't try to backup the mountpoint... ugh
-    sh(&["wsl.exe", "killall", "restic"]).check(false).run()?;
+/// Asks before something that deletes snapshots. --yes answers for scheduled runs, and without a
+/// terminal to ask on it's a no. The prune after each backup doesn't ask, it's part of the routine.
+fn confirm_destructive(what: &str, args: &Args) -> anyhow::Result<()> {
+    if args.yes || args.dry_run {
+        return Ok(());
+    }
+    if !io::stdin().is_terminal() {
+        return Err(anyhow!("{what} needs --yes when not run from a terminal"));
+    }
+    eprint!("{what}? [y/N] ");
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    if answer.trim().eq_ignore_ascii_case("y") {
+        Ok(())
+    } else {
+        Err(anyhow!("{what} not confirmed"))
+    }
+}
 
-    // Securely pass environment variables to WSL (I think...)
-    let mut wslenv = get_env_var("WSLENV").unwrap_or_default();
-    wslenv.push(':');
-    wslenv.push_str(
-        &restic_config_to_env(config)
-            .into_iter()
-            .map(|(k, _)| k)
-            .collect::<Vec<&str>>()
-            .join(":"),
-    );
+/// Prunes every repo, and checks the ones due a check, without backing up first. Upgrades and
+/// hooks are skipped since they're about the backup.
+fn do_maintenance(
+    args: &Args,
+    task_specs: &[TaskSpec],
+    results: &mut Vec<TaskResult>,
+) -> anyhow::Result<()> {
+    for spec in task_specs {
+        match spec {
+            TaskSpec::Upgrade => skip_task(
+                &format!("{} Upgrades", args.os.pretty()),
+                "maintenance run",
+                results,
+            ),
+            TaskSpec::Hook { name, .. } => skip_task(name, "maintenance run", results),
+            TaskSpec::Backup(_) => {}
+        }
+    }
 
-    let mut env = restic_config_to_env(config);
-    env.push(("WSLENV", &wslenv));
+    confirm_destructive("Pruning every repo", args)?;
+    let mut pruned = BTreeSet::new();
+    for (config, via_wsl) in &all_repos(args)? {
+        // The Windows and WSL local repos can be the same repo
+        if !pruned.insert(config.restic_repository.clone()) {
+            continue;
+        }
+        try_task(
+            &repo_task_name("Prune", config, *via_wsl),
+            || prune_repository(config, *via_wsl, os_snapshot_tags(args)),
+            results,
+        );
+        check_repository_if_due(config, *via_wsl, results);
+    }
+    Ok(())
+}
 
-    // Call restic in WSL
-    let mut args = vec![
-        "wsl.exe",
-        "--shell-type",
-        "none", // We don't want bash/zsh to try expanding our exclude glob patterns
-        "/home/linuxbrew/.linuxbrew/bin/restic",
-        "backup",
-        "/home/alex",
-        "--tag",
-        "WSL",
-    ];
-    args.extend(gen_exclude_flags(EXCLUDE_PATTERNS));
+fn do_cleanup(_args: &Args, results: &mut Vec<TaskResult>) -> anyhow::Result<()> {
+    try_task("Clean Up Temp Files", remove_stale_temp_files, results);
+    Ok(())
+}
 
-    sh(&args).env(&env).run()?;
-    log::info!("Backed up WSL filesystem to {}", config.restic_repository);
+/// Every repo this OS backs up to, with whether it has to be reached through WSL
+fn all_repos(args: &Args) -> anyhow::Result<Vec<(ResticConfig, bool)>> {
+    let cloud_config = get_cloud_config()?;
+    if args.os != Os::Windows {
+        return Ok(vec![(cloud_config, false)]);
+    }
+
+    let (windows_to_local_config, wsl_to_local_config) = windows_local_configs(&cloud_config);
+    if let Some(command) = wsl_to_local_config.password_command.as_ref() {
+        check_password_command(command, true)?;
+    }
+    Ok(vec![
+        (windows_to_local_config, false),
+        (wsl_to_local_config, true),
+        (cloud_config, false),
+    ])
+}
+
+fn repo_task_name(action: &str, config: &ResticConfig, via_wsl: bool) -> String {
+    let wsl_suffix = if via_wsl { " (WSL)" } else { "" };
+    format!("{action} {} repo{wsl_suffix}", config.name)
+}
+
+fn do_init(args: &Args, results: &mut Vec<TaskResult>) -> anyhow::Result<()> {
+    for (config, via_wsl) in &all_repos(args)? {
+        try_task(
+            &repo_task_name("Init", config, *via_wsl),
+            || {
+                if ensure_repo_initialized(config, *via_wsl)? {
+                    log::info!("Freshly initialized: {}", config.restic_repository);
+                } else {
+                    log::info!("Already existed: {}", config.restic_repository);
+                }
+                Ok(())
+            },
+            results,
+        );
+    }
     Ok(())
 }
 
-fn backup_windows_to(
-    windows_config: &ResticConfig,
-    wsl_config: &ResticConfig,
-    errors: &mut Vec<String>,
-) {
-    try_task(
-        "Backup Windows Filesystem (Local)",
-        || {
-            backup_filesystem_to(
-                WINDOWS_BACKUP_DIRS,
-                windows_config,
-                &["--tag", "Windows", "--use-fs-snapshot"],
-            )
-        },
-        errors,
-    );
-    try_task("Backup WSL (Local)", || backup_wsl(wsl_config), errors);
+fn do_cost(args: &Args, results: &mut Vec<TaskResult>) -> anyhow::Result<()> {
+    for (config, via_wsl) in &all_repos(args)? {
+        try_task(
+            &repo_task_name("Estimate cost of", config, *via_wsl),
+            || report_cost(config, *via_wsl),
+            results,
+        );
+    }
+    Ok(())
 }
 
-fn do_backup_windows(cloud_config: &ResticConfig, errors: &mut Vec<String>) {
-    try_task("Windows Upgrades", do_windows_upgrades, errors);
+fn do_explain(args: &Args, results: &mut Vec<TaskResult>) -> anyhow::Result<()> {
+    let path = args.explain.as_deref().unwrap_or_default();
+    // restic matches against absolute paths
+    let path = std::path::absolute(path)?.to_string_lossy().into_owned();
+    for (config, via_wsl) in &all_repos(args)? {
+        try_task(
+            &repo_task_name("Explain excludes of", config, *via_wsl),
+            || explain_exclusion(config, *via_wsl, &path),
+            results,
+        );
+    }
+    Ok(())
+}
 
-    let windows_to_local_config = ResticConfig {
-        restic_repository: "Z:\\restic".into(),
-        restic_password: cloud_config.restic_password.clone(),
-        aws_access_key_id: None,
-        aws_secret_access_key: None,
-    };
-    let wsl_to_local_config = ResticConfig {
-        restic_repository: "/mnt/c/restic".into(),
-        restic_password: cloud_config.restic_password.clone(),
-        aws_access_key_id: None,
-        aws_secret_access_key: None,
-    };
+fn do_preview_forget(args: &Args, results: &mut Vec<TaskResult>) -> anyhow::Result<()> {
+    for (config, via_wsl) in &all_repos(args)? {
+        try_task(
+            &repo_task_name("Preview forget in", config, *via_wsl),
+            || preview_forget(config, *via_wsl, os_snapshot_tags(args)),
+            results,
+        );
+    }
+    Ok(())
+}
 
-    backup_windows_to(&windows_to_local_config, &wsl_to_local_config, errors);
-    backup_windows_to(cloud_config, cloud_config, errors);
+fn do_snapshots(args: &Args, results: &mut Vec<TaskResult>) -> anyhow::Result<()> {
+    for (config, via_wsl) in &all_repos(args)? {
+        try_task(
+            &repo_task_name("List snapshots in", config, *via_wsl),
+            || report_snapshots(config, *via_wsl),
+            results,
+        );
+    }
+    Ok(())
 }
 
-fn do_backup_macos(cloud_config: &ResticConfig, errors: &mut Vec<String>) {
-    try_task("macOS Upgrades", do_macos_upgrades, errors);
+fn do_compare_repos(args: &Args, results: &mut Vec<TaskResult>) -> anyhow::Result<()> {
+    let Some(Subcommand::CompareRepos { a, b }) = &args.subcommand else {
+        return Ok(());
+    };
+    let repos = all_repos(args)?;
+    // On Windows the WSL local repo comes after the Windows one, so this finds the Windows one
+    let find_repo = |name: &str| {
+        repos
+            .iter()
+            .find(|(config, _)| config.name == name)
+            .ok_or_else(|| anyhow!("Unknown repo: {name}"))
+    };
+    let repo_a = find_repo(a)?;
+    let repo_b = find_repo(b)?;
+
     try_task(
-        "Backup macOS Filesystem",
-        || backup_filesystem_to(MAC_BACKUP_DIRS, cloud_config, &["--tag", "macOS"]),
-        errors,
+        &format!("Compare {a} and {b} repos"),
+        || compare_snapshots(repo_a, repo_b),
+        results,
     );
+    Ok(())
 }
 
-fn do_backup(is_windows: bool) -> Vec<String> {
-    let any_to_cloud_config_func = || -> anyhow::Result<ResticConfig> {
-        Ok(ResticConfig {
-            restic_repository: get_env_var("BACKUPER_RESTIC_REPOSITORY")?,
-            restic_password: get_env_var("BACKUPER_RESTIC_PASSWORD")?,
-            aws_access_key_id: Some(get_env_var("BACKUPER_AWS_ACCESS_KEY_ID")?),
-            aws_secret_access_key: Some(get_env_var("BACKUPER_AWS_SECRET_ACCESS_KEY")?),
-        })
-    };
-    let cloud_config = match any_to_cloud_config_func() {
-        Ok(conf) => conf,
-        Err(e) => return vec![e.to_string()],
+fn do_status(args: &Args, results: &mut Vec<TaskResult>) -> anyhow::Result<()> {
+    for (config, via_wsl) in &all_repos(args)? {
+        try_task(
+            &repo_task_name("Check locks on", config, *via_wsl),
+            || report_locks(config, *via_wsl),
+            results,
+        );
+    }
+    Ok(())
+}
+
+fn do_preview(args: &Args, results: &mut Vec<TaskResult>) -> anyhow::Result<()> {
+    let cloud_config = get_cloud_config()?;
+    let (windows_to_local_config, _) = windows_local_configs(&cloud_config);
+
+    let (repos, tag_args) = if args.os == Os::Windows {
+        (
+            vec![&windows_to_local_config, &cloud_config],
+            ["--tag", "Windows"],
+        )
+    } else {
+        (vec![&cloud_config], ["--tag", args.os.pretty()])
     };
+    let backup_dirs = resolve_backup_dirs(&configured_backup_dirs(args), args)?;
+
+    for config in repos {
+        try_task(
+            &format!("Preview Backup ({})", config.name),
+            || preview_filesystem_backup(&backup_dirs, config, &tag_args),
+            results,
+        );
+    }
+    Ok(())
+}
+
+fn do_audit(args: &Args, results: &mut Vec<TaskResult>) -> anyhow::Result<()> {
+    let cloud_config = get_cloud_config()?;
+    let (windows_to_local_config, _) = windows_local_configs(&cloud_config);
 
-    let mut errors = Vec::new();
-    if is_windows {
-        do_backup_windows(&cloud_config, &mut errors);
+    let (repos, tag) = if args.os == Os::Windows {
+        (vec![&windows_to_local_config, &cloud_config], "Windows")
     } else {
-        do_backup_macos(&cloud_config, &mut errors);
+        (vec![&cloud_config], args.os.pretty())
+    };
+    // Compare against everything configured, including dirs missing on this machine right now
+    let configured = backup_dirs_to_strings(&configured_backup_dirs(args))?;
+
+    for config in repos {
+        try_task(
+            &repo_task_name("Audit", config, false),
+            || report_drift(config, tag, &configured),
+            results,
+        );
     }
-    errors
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct RunSummary<'a> {
+    os: &'a str,
+    started_at: String,
+    duration_secs: u64,
+    errors: &'a [String],
+    tasks: &'a [TaskResult],
+}
+
+fn run_report_command(summary: &RunSummary) -> anyhow::Result<()> {
+    let input = serde_json::to_string(summary)?;
+    sh(REPORT_COMMAND).input(&input).run()
 }
 
 // Stolen from Zed
 fn init_stdout_logger() {
+    if journal::is_connected() {
+        journal::init_logger();
+        return;
+    }
     env_logger::Builder::new()
         .filter_level(log::LevelFilter::Info)
         .parse_default_env()
@@ -402,41 +3421,835 @@ fn init_stdout_logger() {
         .init();
 }
 
+/// The OS being backed up, which picks the backup dirs, task list and snapshot tag
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum Os {
+    Windows,
+    #[value(name = "macos")]
+    Mac,
+    Linux,
+}
+
+// Without the OS argument, it's the one we're running on
+impl Default for Os {
+    fn default() -> Self {
+        if cfg!(windows) {
+            Os::Windows
+        } else if cfg!(target_os = "macos") {
+            Os::Mac
+        } else {
+            Os::Linux
+        }
+    }
+}
+
+impl Os {
+    /// Also the tag this OS's snapshots get
+    fn pretty(self) -> &'static str {
+        match self {
+            Os::Windows => "Windows",
+            Os::Mac => "macOS",
+            Os::Linux => "Linux",
+        }
+    }
+}
+
+/// Without a subcommand, backs up
+#[derive(clap::Subcommand)]
+enum Subcommand {
+    /// Create any repos that don't exist yet
+    Init,
+    /// Remove temp files left over from runs that died
+    Cleanup,
+    /// List the locks held on each repo
+    Status,
+    /// Estimate what each repo costs per month
+    Cost,
+    /// Compare the latest snapshots against the configured backup dirs
+    Audit,
+    /// List the snapshots in each repo
+    Snapshots,
+    /// Report snapshots that are in one repo but not the other
+    CompareRepos { a: String, b: String },
+}
+
+// What the do_* functions look like
+type SubcommandFn = fn(&Args, &mut Vec<TaskResult>) -> anyhow::Result<()>;
+
+/// My personal backup script
+#[derive(clap::Parser)]
+struct Args {
+    #[arg(value_enum, default_value_t)]
+    os: Os,
+    #[command(subcommand)]
+    subcommand: Option<Subcommand>,
+    /// Back up even if none of the backup dirs exist
+    #[arg(long, global = true)]
+    allow_empty: bool,
+    /// Instead of backing up, list the files a backup would add or modify
+    #[arg(long, global = true)]
+    compare_to_snapshot: bool,
+    /// Fail instead of warning when the backup dirs resolve to too many paths
+    #[arg(long, global = true)]
+    fail_on_path_limit: bool,
+    /// Log how many files are in each backup dir and its biggest subdirs
+    #[arg(long, global = true)]
+    file_counts: bool,
+    /// Fail tasks on warnings too
+    #[arg(long, global = true)]
+    strict: bool,
+    /// Skip the tasks that succeeded in the last run
+    #[arg(long, global = true)]
+    skip_completed: bool,
+    /// Accept repos whose ID changed since they were last seen
+    #[arg(long, global = true)]
+    reset_repo_ids: bool,
+    /// Check the repos' estimated sizes leave room under their storage caps before backing up
+    #[arg(long, global = true)]
+    check_quota: bool,
+    /// Tag the snapshots with this note
+    #[arg(long, global = true)]
+    note: Option<String>,
+    /// Instead of backing up, prune every repo and check the ones due a check
+    #[arg(long, global = true)]
+    maintenance: bool,
+    /// Don't ask before deleting snapshots, e.g. for a scheduled --maintenance
+    #[arg(long, short, global = true)]
+    yes: bool,
+    /// Back up one dir at a time first, tagged checkpoint, for seeding a new repo
+    #[arg(long, global = true, conflicts_with = "changed_since")]
+    checkpoint: bool,
+    /// Instead of backing up, show what the keep policy would forget
+    #[arg(long, global = true)]
+    preview_forget: bool,
+    /// Instead of ~/.config/backuper/config.toml
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Instead of backing up, report whether each repo's excludes leave out this path
+    #[arg(long, global = true, value_name = "PATH")]
+    explain: Option<String>,
+    /// Run everything once per profile, each reading BACKUPER_<PROFILE>_* env vars first
+    #[arg(long, global = true, value_delimiter = ',')]
+    profiles: Vec<String>,
+    /// Only back up files modified since then, e.g. 6h or "2024-08-01 09:00", in snapshots tagged
+    /// changed-since. Pruning waits for a full run.
+    #[arg(long, global = true, value_parser = parse_changed_since)]
+    changed_since: Option<chrono::DateTime<chrono::Local>>,
+    /// Show what the backups would do without writing snapshots, upgrading or pruning
+    #[arg(long, global = true)]
+    dry_run: bool,
+}
+
 fn main() -> anyhow::Result<()> {
     init_stdout_logger();
+    progress::install_signal_handler()?;
+    if let Some(path) = HEARTBEAT_FILE {
+        start_heartbeat(path);
+    }
 
-    let mut args_it = env::args();
-    args_it.next();
-    let Some(os) = args_it.next() else {
-        return Err(anyhow!("No OS provided"));
+    let args = <Args as clap::Parser>::parse();
+    config_file::load(args.config.as_deref())?;
+    STRICT_WARNINGS.store(args.strict, Ordering::Relaxed);
+    RESET_REPO_IDS.store(args.reset_repo_ids, Ordering::Relaxed);
+    CHECK_QUOTA.store(args.check_quota, Ordering::Relaxed);
+    CHECKPOINT_BACKUPS.store(args.checkpoint, Ordering::Relaxed);
+    *CHANGED_SINCE.lock().unwrap_or_else(|e| e.into_inner()) = args.changed_since;
+    DRY_RUN.store(args.dry_run, Ordering::Relaxed);
+    if let Some(note) = args.note.as_ref() {
+        let _ = NOTE_TAG.set(note_to_tag(note)?);
+    }
+    // Without --profiles everything runs once, unprefixed
+    let run_profiles: Vec<Option<String>> = if args.profiles.is_empty() {
+        vec![None]
+    } else {
+        args.profiles.iter().cloned().map(Some).collect()
     };
-    let is_windows = match os.as_str() {
-        "windows" => true,
-        "macos" => false,
-        _ => {
-            return Err(anyhow!("Invalid OS provided: {}", os));
-        }
+
+    // One-off commands report straight to the terminal instead of sending an email
+    let mut results = Vec::new();
+    let one_off: Option<SubcommandFn> = match args.subcommand {
+        Some(Subcommand::Init) => Some(do_init),
+        Some(Subcommand::Cleanup) => Some(do_cleanup),
+        Some(Subcommand::Status) => Some(do_status),
+        Some(Subcommand::Cost) => Some(do_cost),
+        Some(Subcommand::Audit) => Some(do_audit),
+        Some(Subcommand::Snapshots) => Some(do_snapshots),
+        Some(Subcommand::CompareRepos { .. }) => Some(do_compare_repos),
+        None if args.compare_to_snapshot => Some(do_preview),
+        None if args.explain.is_some() => Some(do_explain),
+        None if args.preview_forget => Some(do_preview_forget),
+        None => None,
     };
+    if let Some(one_off) = one_off {
+        let mut errors = Vec::new();
+        for profile in &run_profiles {
+            set_run_profile(profile.clone());
+            if let Err(e) = one_off(&args, &mut results) {
+                errors.push(format!("{}{e}", task_name_prefix()));
+            }
+        }
+        errors.extend(task_errors(&results));
+        if !errors.is_empty() {
+            return Err(anyhow!(errors.join("\n")));
+        }
+        return Ok(());
+    }
+
+    if args.skip_completed {
+        let completed = load_completed_tasks()?;
+        log::info!("Skipping {} tasks that succeeded last run", completed.len());
+        let _ = COMPLETED_TASKS.set(completed);
+    }
+
+    let healthcheck_url = env::var("BACKUPER_HEALTHCHECK_URL")
+        .ok()
+        .filter(|url| !url.is_empty());
+    // A dry run shouldn't count as the backup the healthcheck is waiting for
+    let healthcheck_url = healthcheck_url.filter(|_| !args.dry_run);
+    if let Some(url) = &healthcheck_url {
+        ping_healthcheck(url, "/start", "");
+    }
 
+    let started_at = chrono::Local::now();
     let start = time::Instant::now();
-    let errors = do_backup(is_windows);
+    // Errors that stopped the run from getting to any tasks, e.g. missing credentials
+    let mut setup_errors = Vec::new();
+    for profile in &run_profiles {
+        set_run_profile(profile.clone());
+        if let Err(e) = do_backup(&args, &mut results) {
+            setup_errors.push(format!("{}{e}", task_name_prefix()));
+        }
+    }
     let dur = start.elapsed();
 
-    let os_pretty = if is_windows { "Windows" } else { "macOS" };
+    let os_pretty = args.os.pretty();
+    let run_kind = if args.dry_run {
+        "Dry run backup"
+    } else if args.maintenance {
+        "Maintenance"
+    } else {
+        "Backup"
+    };
     let dur_pretty = pretty_duration(dur);
 
+    if !REPORT_COMMAND.is_empty() {
+        let summary_errors = [setup_errors.clone(), task_errors(&results)].concat();
+        let summary = RunSummary {
+            os: os_pretty,
+            started_at: started_at.to_rfc3339(),
+            duration_secs: dur.as_secs(),
+            errors: &summary_errors,
+            tasks: &results,
+        };
+        let mut report_results = Vec::new();
+        try_task(
+            "Report Command",
+            || run_report_command(&summary),
+            &mut report_results,
+        );
+        results.extend(report_results);
+    }
+
+    // A run that never got to its tasks shouldn't forget what the last one finished, and a dry
+    // run's tasks didn't really finish
+    if setup_errors.is_empty() && !args.dry_run {
+        if let Err(e) = save_last_run(&results) {
+            log::warn!("Failed to save task results for --skip-completed: {e}");
+        }
+    }
+    #[cfg(feature = "metrics")]
+    if let Some(path) = METRICS_FILE.filter(|_| !args.dry_run) {
+        if let Err(e) = metrics::write_metrics(Path::new(path), &results) {
+            log::warn!("Failed to write metrics to {path}: {e}");
+        }
+    }
+
+    let errors = [setup_errors, task_errors(&results)].concat();
+    if let Some(url) = &healthcheck_url {
+        if errors.is_empty() {
+            ping_healthcheck(url, "", "");
+        } else {
+            ping_healthcheck(url, "/fail", &errors.join("\n"));
+        }
+    }
+    let notes: Vec<String> = results
+        .iter()
+        .filter_map(|r| match &r.status {
+            TaskStatus::Warned(warning) => Some(format!(
+                "[{} in {}] Warning: {warning}",
+                r.name,
+                pretty_duration(r.duration)
+            )),
+            TaskStatus::Skipped(reason) => Some(format!("Skipped {}: {reason}", r.name)),
+            _ => None,
+        })
+        .collect();
+    let notes_section = if notes.is_empty() {
+        String::new()
+    } else {
+        format!("\n\n{}", notes.join("\n"))
+    };
+
     let subject: String;
     let body: String;
     if errors.is_empty() {
-        subject = format!("Backup {os_pretty} succeeded");
-        body = format!("Completed in {dur_pretty}\n\nHope you're having a nice day :)");
+        subject = format!("{run_kind} {os_pretty} succeeded");
+        body =
+            format!("Completed in {dur_pretty}{notes_section}\n\nHope you're having a nice day :)");
     } else {
         let error_word = if errors.len() == 1 { "error" } else { "errors" };
         let joined_errors = errors.join("\n");
-        subject = format!("Backup {os_pretty} failed! {} {error_word}", errors.len());
-        body = format!("Completed in {dur_pretty}\n\n{joined_errors}");
+        subject = format!(
+            "{run_kind} {os_pretty} failed! {} {error_word}",
+            errors.len()
+        );
+        body = format!("Completed in {dur_pretty}\n\n{joined_errors}{notes_section}");
     }
 
-    notify(&subject, &body)?;
+    log::info!("{subject}\n{body}");
+    #[cfg(feature = "notifiers")]
+    let notify_failed = notify::notify(&subject, &body)
+        .inspect_err(|e| log::warn!("Failed to send notification: {e:#}"))
+        .is_err();
+    #[cfg(not(feature = "notifiers"))]
+    let notify_failed = false;
+    // Counted as a failure since nobody hears about the run otherwise
+    let failures = errors.len() + usize::from(notify_failed);
+
+    // Exit with the number of failures so cron or launchd notice, capped below the codes shells
+    // reserve for themselves
+    if failures > 0 {
+        process::exit(failures.min(125) as i32);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh dir in the temp dir, removed with everything in it when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn create(name: &str) -> Self {
+            let path = env::temp_dir().join(format!(
+                "backuper-test-{name}-{}-{:016x}",
+                process::id(),
+                random_suffix()
+            ));
+            fs::create_dir(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Runs the closure when dropped, to reset a global even if the test fails
+    struct OnDrop<F: FnMut()>(F);
+
+    impl<F: FnMut()> Drop for OnDrop<F> {
+        fn drop(&mut self) {
+            (self.0)()
+        }
+    }
+
+    // Tests that set env vars or globals can't run at the same time
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// The cloud config with the required env vars set, plus `vars`
+    fn cloud_config_with(vars: &[(&str, &str)]) -> anyhow::Result<ResticConfig> {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let required = [
+            ("BACKUPER_RESTIC_REPOSITORY", "s3:s3.example.com/bucket"),
+            ("BACKUPER_RESTIC_PASSWORD", "password"),
+            ("BACKUPER_AWS_ACCESS_KEY_ID", "key-id"),
+            ("BACKUPER_AWS_SECRET_ACCESS_KEY", "secret"),
+        ];
+        for (var, value) in required.iter().chain(vars) {
+            env::set_var(var, value);
+        }
+        let config = get_cloud_config();
+        for (var, _) in vars {
+            env::remove_var(var);
+        }
+        config
+    }
+
+    #[test]
+    fn ignore_inode_reaches_backup_flags() {
+        let config = cloud_config_with(&[("BACKUPER_IGNORE_INODE", "true")]).unwrap();
+        assert!(restic_backup_flags(&config).contains(&"--ignore-inode".to_owned()));
+        let (local, _) = windows_local_configs(&config);
+        assert!(restic_backup_flags(&local).contains(&"--ignore-inode".to_owned()));
+
+        let config = cloud_config_with(&[]).unwrap();
+        assert!(!restic_backup_flags(&config).contains(&"--ignore-inode".to_owned()));
+    }
+
+    #[test]
+    fn ignore_ctime_reaches_backup_flags() {
+        let config = cloud_config_with(&[("BACKUPER_IGNORE_CTIME", "true")]).unwrap();
+        assert!(restic_backup_flags(&config).contains(&"--ignore-ctime".to_owned()));
+        assert!(cloud_config_with(&[("BACKUPER_IGNORE_CTIME", "yes")]).is_err());
+    }
+
+    #[test]
+    fn retry_lock_reaches_repo_flags() {
+        let config = cloud_config_with(&[("BACKUPER_RETRY_LOCK", "1h30m")]).unwrap();
+        assert_eq!(restic_repo_flags(&config), ["--retry-lock", "1h30m"]);
+
+        for invalid in ["30", "m", "30 minutes", "1h30"] {
+            assert!(cloud_config_with(&[("BACKUPER_RETRY_LOCK", invalid)]).is_err());
+        }
+    }
+
+    #[test]
+    fn order_is_parsed() {
+        let config = cloud_config_with(&[("BACKUPER_ORDER", "-1")]).unwrap();
+        assert_eq!(config.order, -1);
+        assert_eq!(cloud_config_with(&[]).unwrap().order, 0);
+        assert!(cloud_config_with(&[("BACKUPER_ORDER", "first")]).is_err());
+    }
+
+    #[test]
+    fn cost_per_gb_month_sets_cost_rate() {
+        let config = cloud_config_with(&[("BACKUPER_COST_PER_GB_MONTH", "0.012")]).unwrap();
+        assert_eq!(cost_rate(&config), 0.012);
+        let config = cloud_config_with(&[]).unwrap();
+        assert_eq!(cost_rate(&config), DEFAULT_COST_PER_GB_MONTH);
+        assert!(cloud_config_with(&[("BACKUPER_COST_PER_GB_MONTH", "cheap")]).is_err());
+    }
+
+    #[test]
+    fn parallel_backups_is_parsed() {
+        let config = cloud_config_with(&[("BACKUPER_PARALLEL_BACKUPS", "3")]).unwrap();
+        assert_eq!(config.parallel_backups, 3);
+        assert!(cloud_config_with(&[("BACKUPER_PARALLEL_BACKUPS", "-1")]).is_err());
+    }
+
+    #[test]
+    fn read_concurrency_reaches_backup_flags() {
+        let config = cloud_config_with(&[("BACKUPER_READ_CONCURRENCY", "4")]).unwrap();
+        let flags = restic_backup_flags(&config);
+        assert!(flags.windows(2).any(|w| w == ["--read-concurrency", "4"]));
+        assert!(cloud_config_with(&[("BACKUPER_READ_CONCURRENCY", "0")]).is_err());
+    }
+
+    #[test]
+    fn pack_size_reaches_backup_flags() {
+        let config = cloud_config_with(&[("BACKUPER_PACK_SIZE_MIB", "32")]).unwrap();
+        let flags = restic_backup_flags(&config);
+        assert!(flags.windows(2).any(|w| w == ["--pack-size", "32"]));
+        assert!(cloud_config_with(&[("BACKUPER_PACK_SIZE_MIB", "512")]).is_err());
+    }
+
+    #[test]
+    fn compression_reaches_backup_flags_over_profile() {
+        let config = cloud_config_with(&[
+            ("BACKUPER_RESTIC_PROFILE", "low-cpu"),
+            ("BACKUPER_COMPRESSION", "max"),
+        ])
+        .unwrap();
+        let flags = restic_backup_flags(&config);
+        assert!(flags.windows(2).any(|w| w == ["--compression", "max"]));
+        // The rest of the profile still applies
+        assert!(flags.windows(2).any(|w| w == ["--read-concurrency", "1"]));
+        assert!(cloud_config_with(&[("BACKUPER_COMPRESSION", "fast")]).is_err());
+    }
+
+    #[test]
+    fn profile_reaches_backup_flags() {
+        let config = cloud_config_with(&[("BACKUPER_RESTIC_PROFILE", "fast-local")]).unwrap();
+        let flags = restic_backup_flags(&config);
+        assert!(flags.windows(2).any(|w| w == ["--read-concurrency", "8"]));
+        assert!(flags.windows(2).any(|w| w == ["--pack-size", "64"]));
+        assert!(flags.windows(2).any(|w| w == ["--compression", "auto"]));
+        assert!(cloud_config_with(&[("BACKUPER_RESTIC_PROFILE", "turbo")]).is_err());
+    }
+
+    #[test]
+    fn limit_upload_reaches_backup_flags() {
+        let config = cloud_config_with(&[("BACKUPER_LIMIT_UPLOAD_KIB", "500")]).unwrap();
+        let flags = restic_backup_flags(&config);
+        assert!(flags.windows(2).any(|w| w == ["--limit-upload", "500"]));
+    }
+
+    #[test]
+    fn keep_policy_reaches_forget_flags() {
+        let config = cloud_config_with(&[
+            ("BACKUPER_KEEP_DAILY", "3"),
+            ("BACKUPER_KEEP_WEEKLY", "2"),
+            ("BACKUPER_KEEP_MONTHLY", "1"),
+        ])
+        .unwrap();
+        let flags = forget_flags(&config, &["Linux"]);
+        assert_eq!(
+            flags,
+            [
+                "--keep-daily",
+                "3",
+                "--keep-weekly",
+                "2",
+                "--keep-monthly",
+                "1",
+                "--tag",
+                "Linux"
+            ]
+        );
+    }
+
+    #[test]
+    fn check_read_data_percent_reaches_check_flags() {
+        let config = cloud_config_with(&[("BACKUPER_CHECK_READ_DATA_PERCENT", "2.5")]).unwrap();
+        assert_eq!(check_flags(&config), ["--read-data-subset=2.5%"]);
+        let config = cloud_config_with(&[]).unwrap();
+        assert!(check_flags(&config).is_empty());
+        for percent in ["0", "150", "NaN"] {
+            assert!(cloud_config_with(&[("BACKUPER_CHECK_READ_DATA_PERCENT", percent)]).is_err());
+        }
+    }
+
+    #[test]
+    fn check_interval_is_parsed() {
+        let config = cloud_config_with(&[("BACKUPER_CHECK_INTERVAL_DAYS", "14")]).unwrap();
+        assert_eq!(
+            config.check_interval,
+            Some(Duration::from_secs(14 * 24 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn init_if_missing_is_parsed() {
+        let config = cloud_config_with(&[("BACKUPER_INIT_IF_MISSING", "true")]).unwrap();
+        assert!(config.init_if_missing);
+        assert!(cloud_config_with(&[("BACKUPER_INIT_IF_MISSING", "yes")]).is_err());
+    }
+
+    #[test]
+    fn storage_cap_is_parsed() {
+        let config = cloud_config_with(&[("BACKUPER_STORAGE_CAP_GB", "10")]).unwrap();
+        assert_eq!(config.storage_cap_gb, Some(10.0));
+        let result = cloud_config_with(&[("BACKUPER_STORAGE_CAP_GB", "10GB")]);
+        assert!(result.is_err_and(|e| e.to_string().contains("BACKUPER_STORAGE_CAP_GB")));
+    }
+
+    #[test]
+    fn exclude_tier_is_parsed() {
+        let config = cloud_config_with(&[("BACKUPER_EXCLUDE_TIER", "aggressive")]).unwrap();
+        assert!(config.exclude_tier == ExcludeTier::Aggressive);
+        assert!(cloud_config_with(&[("BACKUPER_EXCLUDE_TIER", "all")]).is_err());
+    }
+
+    #[test]
+    fn password_command_reaches_restic_env() {
+        let config =
+            cloud_config_with(&[("BACKUPER_RESTIC_PASSWORD_COMMAND", "true pass")]).unwrap();
+        let env = restic_own_env(&config);
+        assert!(env.contains(&("RESTIC_PASSWORD_COMMAND", "true pass")));
+        assert!(!env.iter().any(|(name, _)| *name == "RESTIC_PASSWORD"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wsl_password_commands_are_looked_up_in_wsl() {
+        check_password_command("sh -c true", false).unwrap();
+        assert!(check_password_command("missing-password-tool", false).is_err());
+        // Found on our PATH, but there's no WSL here to find it in
+        if !program_exists("wsl.exe") {
+            let err = check_password_command("sh -c true", true).unwrap_err();
+            assert!(err.to_string().contains("in WSL"));
+        }
+    }
+
+    #[test]
+    fn temp_files_get_new_unique_names() {
+        let a = TempFile::create("test", "a").unwrap();
+        let b = TempFile::create("test", "b").unwrap();
+        assert_ne!(a.path, b.path);
+        assert_eq!(fs::read_to_string(&b.path).unwrap(), "b");
+        let name = a.path.file_name().unwrap().to_str().unwrap();
+        assert_eq!(temp_file_owner(name), Some(process::id()));
+        assert!(create_new_file(&a.path, "c", CREATED_FILE_MODE).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_private_file_replaces_symlinks() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::create("write-private");
+        let target = dir.0.join("target");
+        fs::write(&target, "untouched").unwrap();
+        let link = dir.0.join("link");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        write_private_file(&link, "new").unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "untouched");
+        let metadata = fs::symlink_metadata(&link).unwrap();
+        assert!(metadata.is_file());
+        assert_eq!(metadata.permissions().mode() & 0o777, CREATED_FILE_MODE);
+        assert_eq!(fs::read_to_string(&link).unwrap(), "new");
+        // Only the file itself is left behind
+        assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 2);
+
+        // Shared files are replaced the same way, just readable by others
+        write_shared_file(&link, "shared").unwrap();
+        let metadata = fs::symlink_metadata(&link).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, SHARED_FILE_MODE);
+    }
+
+    /// resolve_backup_dirs with BACKUPER_BROKEN_SYMLINKS set to `mode`
+    fn resolve_with_broken_symlinks(
+        backup_dirs: &[BackupDir],
+        mode: &str,
+    ) -> anyhow::Result<Vec<String>> {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let args = <Args as clap::Parser>::parse_from(["backuper", "--allow-empty"]);
+        env::set_var("BACKUPER_BROKEN_SYMLINKS", mode);
+        let resolved = resolve_backup_dirs(backup_dirs, &args);
+        env::remove_var("BACKUPER_BROKEN_SYMLINKS");
+        resolved
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn broken_symlinks_follow_the_configured_mode() {
+        let dir = TempDir::create("broken-symlinks");
+        let existing = dir.0.join("existing").to_string_lossy().into_owned();
+        fs::create_dir(&existing).unwrap();
+        let link = dir.0.join("link").to_string_lossy().into_owned();
+        std::os::unix::fs::symlink(dir.0.join("unmounted"), &link).unwrap();
+        let backup_dirs = [BackupDir::Root(&existing), BackupDir::Root(&link)];
+
+        let resolved = resolve_with_broken_symlinks(&backup_dirs, "skip").unwrap();
+        assert_eq!(resolved, [existing.as_str()]);
+        let resolved = resolve_with_broken_symlinks(&backup_dirs, "include").unwrap();
+        assert_eq!(resolved, [existing.as_str(), link.as_str()]);
+        let err = resolve_with_broken_symlinks(&backup_dirs, "fail").unwrap_err();
+        assert!(err.to_string().contains(&link));
+    }
+
+    #[test]
+    fn single_files_are_backed_up_as_is() {
+        let dir = TempDir::create("single-file");
+        let backup_dir = dir.0.join("docs").to_string_lossy().into_owned();
+        fs::create_dir(&backup_dir).unwrap();
+        let file = dir.0.join("config").to_string_lossy().into_owned();
+        fs::write(&file, "").unwrap();
+        let backup_dirs = [BackupDir::Root(&backup_dir), BackupDir::Root(&file)];
+
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let args = <Args as clap::Parser>::parse_from(["backuper"]);
+        let resolved = resolve_backup_dirs(&backup_dirs, &args).unwrap();
+        assert_eq!(resolved, [backup_dir.as_str(), file.as_str()]);
+        assert_eq!(files_from_input(&resolved), format!("{backup_dir}\n{file}"));
+    }
+
+    #[test]
+    fn dry_runs_dont_unlock_stale_locks() {
+        let created_at = chrono::Local::now().naive_local() - STALE_LOCK_AGE * 2;
+        let mut calls = 0;
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        DRY_RUN.store(true, Ordering::Relaxed);
+        let _reset = OnDrop(|| DRY_RUN.store(false, Ordering::Relaxed));
+        let result = retry_if_stale_lock(&ResticConfig::default(), false, || {
+            calls += 1;
+            Err(restic_error::ResticError::Locked {
+                repo: String::new(),
+                lock: restic_error::LockInfo {
+                    created_at: Some(created_at),
+                    ..Default::default()
+                },
+            }
+            .into())
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unchanged_files_only_skip_the_backups() {
+        let args =
+            <Args as clap::Parser>::parse_from(["backuper", "linux", "--changed-since", "1h"]);
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        *CHANGED_SINCE.lock().unwrap() = args.changed_since;
+        let _reset = OnDrop(|| *CHANGED_SINCE.lock().unwrap_or_else(|e| e.into_inner()) = None);
+        let specs = [
+            TaskSpec::Hook {
+                name: "Before",
+                cmd: &["true"],
+            },
+            TaskSpec::Backup("Cloud"),
+        ];
+        let mut results = Vec::new();
+        run_task_specs(&specs, &args, &[], &ResticConfig::default(), &mut results);
+        assert_eq!(results.len(), 2);
+        assert!(matches!(results[0].status, TaskStatus::Succeeded));
+        assert_eq!(results[1].name, "Backup Linux Filesystem");
+        assert!(
+            matches!(&results[1].status, TaskStatus::Skipped(reason) if reason == "no files changed")
+        );
+    }
+
+    #[test]
+    fn changed_since_snapshots_are_forgotten_by_tags() {
+        let config = cloud_config_with(&[("BACKUPER_KEEP_DAILY", "3")]).unwrap();
+        let flags = changed_since_forget_flags(&config, &["Windows", "WSL"]);
+        assert_eq!(
+            flags,
+            [
+                "--group-by",
+                "host,tags",
+                "--keep-daily",
+                "3",
+                "--tag",
+                "Windows,changed-since",
+                "--tag",
+                "WSL,changed-since"
+            ]
+        );
+    }
+
+    #[test]
+    fn warned_backups_still_get_pruned() {
+        let result = |status| TaskResult {
+            name: "Backup Linux Filesystem".into(),
+            duration: Duration::ZERO,
+            status,
+        };
+        assert!(last_task_succeeded(&[result(TaskStatus::Warned(
+            "some files were unreadable".into()
+        ))]));
+        assert!(!last_task_succeeded(&[result(TaskStatus::Failed(
+            "no repo".into()
+        ))]));
+    }
+
+    #[test]
+    fn restic_errors_are_classified_by_cause() {
+        let classify = |stderr: &str| {
+            let err = ShError {
+                code: Some(1),
+                stderr: stderr.to_owned(),
+            };
+            restic_error::classify(err.into(), "repo")
+        };
+        let location =
+            "\nIs there a repository at the following location?\ns3:s3.example.com/bucket";
+
+        let missing = classify(&format!(
+            "Fatal: unable to open config file: Stat: The specified key does not exist.{location}"
+        ));
+        assert!(matches!(
+            missing.downcast_ref(),
+            Some(restic_error::ResticError::NotInitialized { .. })
+        ));
+        let denied = classify(&format!(
+            "Fatal: unable to open config file: Stat: Access Denied.{location}"
+        ));
+        assert!(matches!(
+            denied.downcast_ref(),
+            Some(restic_error::ResticError::AccessDenied { .. })
+        ));
+        let forbidden = classify("Fatal: unable to open config file: Stat: 403 Forbidden");
+        assert!(matches!(
+            forbidden.downcast_ref(),
+            Some(restic_error::ResticError::AccessDenied { .. })
+        ));
+        let unreachable = classify(&format!(
+            "Fatal: unable to open config file: Stat: dial tcp: i/o timeout{location}"
+        ));
+        assert!(unreachable
+            .downcast_ref::<restic_error::ResticError>()
+            .is_none());
+    }
+
+    #[test]
+    fn incomplete_snapshots_report_restic_errors_as_text() {
+        let stderr = r#"{"message_type":"error","error":{"message":"open /a: permission denied"},"during":"archival","item":"/a"}
+{"message_type":"status","percent_done":1}
+Warning: at least one source file could not be read"#;
+        let err = ShError {
+            code: Some(3),
+            stderr: stderr.to_owned(),
+        };
+        let report = restic_error::classify(err.into(), "repo").to_string();
+        assert!(report.contains("/a: open /a: permission denied"));
+        assert!(report.contains("Warning: at least one source file could not be read"));
+        assert!(!report.contains("message_type"));
+    }
+
+    #[test]
+    fn changed_files_are_capped() {
+        let paths = vec![String::new(); MAX_BACKUP_PATHS + 1];
+        let args = <Args as clap::Parser>::parse_from(["backuper", "--fail-on-path-limit"]);
+        assert!(check_path_limit(&paths, &args).is_err());
+        let args = <Args as clap::Parser>::parse_from(["backuper"]);
+        assert!(check_path_limit(&paths, &args).is_ok());
+    }
+
+    #[test]
+    fn backup_summaries_add_up() {
+        let output = r#"{"message_type":"status","percent_done":0.5}
+{"message_type":"summary","total_files_processed":10,"files_new":2,"data_added":1500000}"#;
+        let mut summary = BackupSummary::of(output);
+        summary.add(output);
+        assert_eq!(summary.to_string(), "20 files, 4 new, 3.0 MB added");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn timeouts_kill_what_the_command_started() {
+        let start = time::Instant::now();
+        let result = sh(&["sh", "-c", "sleep 60 & sleep 60"])
+            .timeout(Duration::from_secs(1))
+            .run();
+        assert!(result.unwrap_err().to_string().contains("timed out"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn weekdays_are_parsed() {
+        let config = cloud_config_with(&[("BACKUPER_WEEKDAYS", "mon, Wednesday")]).unwrap();
+        assert_eq!(
+            config.weekdays,
+            [chrono::Weekday::Mon, chrono::Weekday::Wed]
+        );
+        assert!(cloud_config_with(&[("BACKUPER_WEEKDAYS", "mon,someday")]).is_err());
+    }
+
+    #[test]
+    fn concurrency_groups_run_serially_within_and_parallel_between() {
+        let config = cloud_config_with(&[("BACKUPER_CONCURRENCY_GROUP", "cloud")]).unwrap();
+        assert_eq!(config.concurrency_group, "cloud");
+
+        // (group, item) pairs, tracking how many run at once overall and per group
+        let items = [("", 1), ("", 2), ("cloud", 3), ("cloud", 4)];
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        let running_in_group = [AtomicUsize::new(0), AtomicUsize::new(0)];
+        let max_in_group = AtomicUsize::new(0);
+        run_in_concurrency_groups(
+            &items,
+            |(group, _)| group,
+            |(group, _), _| {
+                let group_running = &running_in_group[usize::from(!group.is_empty())];
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now, Ordering::SeqCst);
+                let now_in_group = group_running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_group.fetch_max(now_in_group, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(50));
+                group_running.fetch_sub(1, Ordering::SeqCst);
+                running.fetch_sub(1, Ordering::SeqCst);
+            },
+            &mut Vec::new(),
+        );
+        assert_eq!(max_in_group.load(Ordering::SeqCst), 1);
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,119 @@
+use std::{collections::BTreeMap, fmt::Write, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    state_dir, write_private_file, write_shared_file, TaskResult, TaskStatus, DURATION_BUCKETS_SECS,
+};
+
+/// Task duration counts since the buckets were last changed, kept in the state dir between runs
+#[derive(Default, Serialize, Deserialize)]
+struct Histogram {
+    bounds: Vec<f64>,
+    // Cumulative, one per bound, like the _bucket samples
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        if self.bounds != DURATION_BUCKETS_SECS {
+            // Counts for other bounds can't be converted, so start over
+            *self = Histogram {
+                bounds: DURATION_BUCKETS_SECS.to_vec(),
+                buckets: vec![0; DURATION_BUCKETS_SECS.len()],
+                ..Default::default()
+            };
+        }
+        for (bound, bucket) in self.bounds.iter().zip(&mut self.buckets) {
+            if secs <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn load_histograms() -> anyhow::Result<BTreeMap<String, Histogram>> {
+    let path = state_dir()?.join("duration-histograms.json");
+    match fs::read_to_string(&path) {
+        Ok(contents) => Ok(serde_json::from_str(&contents)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Adds the run's task durations to the saved histograms and writes everything as OpenMetrics
+/// text, e.g. for node_exporter's textfile collector
+pub fn write_metrics(path: &Path, results: &[TaskResult]) -> anyhow::Result<()> {
+    let mut histograms = load_histograms()?;
+    for result in results {
+        if !matches!(result.status, TaskStatus::Skipped(_)) {
+            let histogram = histograms.entry(result.name.clone()).or_default();
+            histogram.observe(result.duration.as_secs_f64());
+        }
+    }
+    fs::create_dir_all(state_dir()?)?;
+    write_private_file(
+        &state_dir()?.join("duration-histograms.json"),
+        &serde_json::to_string_pretty(&histograms)?,
+    )?;
+
+    let mut out = String::new();
+    writeln!(out, "# TYPE backuper_last_run_timestamp_seconds gauge")?;
+    writeln!(
+        out,
+        "backuper_last_run_timestamp_seconds {}",
+        chrono::Utc::now().timestamp()
+    )?;
+
+    writeln!(out, "# TYPE backuper_task_success gauge")?;
+    for result in results {
+        let success = match result.status {
+            TaskStatus::Succeeded | TaskStatus::Warned(_) => 1,
+            TaskStatus::Failed(_) => 0,
+            TaskStatus::Skipped(_) => continue,
+        };
+        let task = escape_label(&result.name);
+        writeln!(out, "backuper_task_success{{task=\"{task}\"}} {success}")?;
+    }
+
+    writeln!(out, "# TYPE backuper_task_duration_seconds histogram")?;
+    for (name, histogram) in &histograms {
+        let task = escape_label(name);
+        for (bound, bucket) in histogram.bounds.iter().zip(&histogram.buckets) {
+            writeln!(
+                out,
+                "backuper_task_duration_seconds_bucket{{task=\"{task}\",le=\"{bound}\"}} {bucket}"
+            )?;
+        }
+        writeln!(
+            out,
+            "backuper_task_duration_seconds_bucket{{task=\"{task}\",le=\"+Inf\"}} {}",
+            histogram.count
+        )?;
+        writeln!(
+            out,
+            "backuper_task_duration_seconds_count{{task=\"{task}\"}} {}",
+            histogram.count
+        )?;
+        writeln!(
+            out,
+            "backuper_task_duration_seconds_sum{{task=\"{task}\"}} {}",
+            histogram.sum
+        )?;
+    }
+    writeln!(out, "# EOF")?;
+
+    // Written then renamed, so the collector never reads a half-written file
+    write_shared_file(path, &out)
+}
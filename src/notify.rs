@@ -0,0 +1,31 @@
+use lettre::{
+    message::header::ContentType, transport::smtp::authentication::Credentials, Message,
+    SmtpTransport, Transport,
+};
+
+use crate::get_env_var;
+
+pub fn notify(subject: &str, body: &str) -> anyhow::Result<()> {
+    // Grab credentials
+    let email_address = get_env_var("BACKUPER_EMAIL_ADDRESS")?;
+    let email_password = get_env_var("BACKUPER_EMAIL_PASSWORD")?;
+
+    // Build the email
+    let email = Message::builder()
+        .from(format!("Backup Script <{email_address}>").parse()?)
+        .to(format!("Alex Ozer <{email_address}>").parse()?)
+        .subject(subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(body.to_owned())?;
+
+    let creds = Credentials::new(email_address, email_password);
+
+    // Open a remote connection to gmail
+    let mailer = SmtpTransport::relay("smtp.gmail.com")?
+        .credentials(creds)
+        .build();
+
+    // Send the email
+    mailer.send(&email)?;
+    Ok(())
+}
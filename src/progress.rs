@@ -0,0 +1,88 @@
+use std::{sync::Mutex, time};
+
+use crate::pretty_duration;
+
+// There can be several tasks running at once with concurrency groups
+static RUNNING_TASKS: Mutex<Vec<(String, time::Instant)>> = Mutex::new(Vec::new());
+static LATEST_OUTPUT: Mutex<Option<String>> = Mutex::new(None);
+
+pub fn task_started(name: &str) {
+    if let Ok(mut tasks) = RUNNING_TASKS.lock() {
+        tasks.push((name.to_owned(), time::Instant::now()));
+    }
+}
+
+pub fn task_finished(name: &str) {
+    if let Ok(mut tasks) = RUNNING_TASKS.lock() {
+        if let Some(i) = tasks.iter().position(|(n, _)| n == name) {
+            tasks.remove(i);
+        }
+    }
+}
+
+pub fn any_task_running() -> bool {
+    RUNNING_TASKS.lock().is_ok_and(|tasks| !tasks.is_empty())
+}
+
+/// Remembers the last line a command printed, e.g. a restic `--json` status message
+pub fn record_output_line(line: &str) {
+    let line = line.trim();
+    if line.is_empty() {
+        return;
+    }
+    if let Ok(mut latest) = LATEST_OUTPUT.lock() {
+        *latest = Some(line.to_owned());
+    }
+}
+
+#[cfg(unix)]
+fn describe_output(line: &str) -> String {
+    let Ok(msg) = serde_json::from_str::<serde_json::Value>(line) else {
+        return line.to_owned();
+    };
+    if msg["message_type"] != "status" {
+        return line.to_owned();
+    }
+    format!(
+        "{:.1}% done, {}/{} files",
+        msg["percent_done"].as_f64().unwrap_or_default() * 100.0,
+        msg["files_done"].as_u64().unwrap_or_default(),
+        msg["total_files"].as_u64().unwrap_or_default()
+    )
+}
+
+#[cfg(unix)]
+fn log_progress() {
+    let tasks = RUNNING_TASKS.lock().map(|t| t.clone()).unwrap_or_default();
+    if tasks.is_empty() {
+        log::info!("Progress: no task running");
+    }
+    for (name, start) in tasks {
+        log::info!(
+            "Progress: running {name} for {}",
+            pretty_duration(start.elapsed())
+        );
+    }
+
+    let latest = LATEST_OUTPUT.lock().ok().and_then(|l| l.clone());
+    if let Some(line) = latest {
+        log::info!("Progress: latest output: {}", describe_output(&line));
+    }
+}
+
+/// Logs the running tasks and latest command output whenever we get SIGUSR1
+#[cfg(unix)]
+pub fn install_signal_handler() -> anyhow::Result<()> {
+    let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGUSR1])?;
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            log_progress();
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn install_signal_handler() -> anyhow::Result<()> {
+    Ok(())
+}
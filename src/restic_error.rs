@@ -0,0 +1,155 @@
+use std::fmt;
+
+use crate::ShError;
+
+/// Failures from restic that we know how to explain better than its raw stderr
+#[derive(Debug)]
+pub enum ResticError {
+    Locked { repo: String, lock: LockInfo },
+    NotInitialized { repo: String },
+    // The backend refused the credentials, e.g. a 403 from S3
+    AccessDenied { repo: String, stderr: String },
+    // restic exits with 3 when it saved a snapshot but couldn't read some of the files
+    IncompleteSnapshot { repo: String, stderr: String },
+}
+
+/// Whatever we managed to parse out of restic's "repository is already locked" message
+#[derive(Debug, Default)]
+pub struct LockInfo {
+    pub pid: Option<u32>,
+    pub host: Option<String>,
+    pub created_at: Option<chrono::NaiveDateTime>,
+}
+
+impl fmt::Display for ResticError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResticError::Locked { repo, lock } => {
+                write!(f, "repo {repo} is locked by another process")?;
+                if let Some(created_at) = lock.created_at {
+                    write!(f, " since {created_at}")?;
+                }
+                match (lock.pid, &lock.host) {
+                    (Some(pid), Some(host)) => write!(f, " (PID {pid} on {host})"),
+                    (Some(pid), None) => write!(f, " (PID {pid})"),
+                    (None, Some(host)) => write!(f, " (on {host})"),
+                    (None, None) => Ok(()),
+                }
+            }
+            ResticError::NotInitialized { repo } => {
+                write!(f, "repo {repo} does not exist (run the init subcommand)")
+            }
+            ResticError::AccessDenied { repo, stderr } => {
+                write!(
+                    f,
+                    "access to repo {repo} was denied, check its credentials\n{stderr}"
+                )
+            }
+            ResticError::IncompleteSnapshot { repo, stderr } => {
+                write!(
+                    f,
+                    "snapshot in {repo} is missing files that couldn't be read\n{stderr}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResticError {}
+
+/// Turns a failed restic command into a `ResticError` if we recognize the failure,
+/// otherwise passes the error through untouched
+pub fn classify(err: anyhow::Error, repo: &str) -> anyhow::Error {
+    let Some(sh_err) = err.downcast_ref::<ShError>() else {
+        return err;
+    };
+    if sh_err.code == Some(3) {
+        return ResticError::IncompleteSnapshot {
+            repo: repo.to_owned(),
+            stderr: readable_stderr(&sh_err.stderr),
+        }
+        .into();
+    }
+    if let Some(lock) = parse_lock_info(&sh_err.stderr) {
+        return ResticError::Locked {
+            repo: repo.to_owned(),
+            lock,
+        }
+        .into();
+    }
+    let stderr = &sh_err.stderr;
+    let lowercase = stderr.to_lowercase();
+    let http_403 = stderr
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .any(|word| word == "403");
+    if http_403 || lowercase.contains("access denied") || lowercase.contains("accessdenied") {
+        return ResticError::AccessDenied {
+            repo: repo.to_owned(),
+            stderr: readable_stderr(stderr),
+        }
+        .into();
+    }
+    // restic adds "Is there a repository at the following location?" to every failure to open the
+    // config, denied access and network errors included, so it takes the file actually missing
+    if stderr.contains("repository does not exist")
+        || (stderr.contains("unable to open config file")
+            && (stderr.contains("does not exist") || stderr.contains("no such file or directory")))
+    {
+        return ResticError::NotInitialized {
+            repo: repo.to_owned(),
+        }
+        .into();
+    }
+    err
+}
+
+/// restic's stderr with the JSON lines from `--json` turned back into text, one per error, and
+/// the other lines as they are
+fn readable_stderr(stderr: &str) -> String {
+    let mut lines = Vec::new();
+    for line in stderr.lines() {
+        let message = serde_json::from_str::<serde_json::Value>(line).ok();
+        let Some(message) = message.filter(serde_json::Value::is_object) else {
+            lines.push(line.to_owned());
+            continue;
+        };
+        let text = |value: &serde_json::Value| value.as_str().unwrap_or_default().to_owned();
+        match message["message_type"].as_str() {
+            // e.g. {"message_type":"error","error":{"message":"permission denied"},"item":"/a"}
+            Some("error") => {
+                let error = text(&message["error"]["message"]);
+                match message["item"].as_str() {
+                    Some(item) if !item.is_empty() => lines.push(format!("{item}: {error}")),
+                    _ => lines.push(error),
+                }
+            }
+            Some("exit_error") => lines.push(text(&message["message"])),
+            _ => {}
+        }
+    }
+    lines.join("\n")
+}
+
+// Expected format, give or take the "exclusively":
+//
+//   unable to create lock in backend: repository is already locked by PID 1234 on host by user (UID 501, GID 20)
+//   lock was created at 2024-08-01 10:00:00 (2h3m4s ago)
+fn parse_lock_info(stderr: &str) -> Option<LockInfo> {
+    let (_, locked_by) = stderr.split_once("repository is already locked")?;
+
+    let mut lock = LockInfo::default();
+    if let Some((_, rest)) = locked_by.split_once("by PID ") {
+        let (pid, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        lock.pid = pid.trim().parse().ok();
+        if let Some(rest) = rest.strip_prefix("on ") {
+            let host = rest.split([' ', '\n']).next().unwrap_or_default();
+            lock.host = Some(host.to_owned()).filter(|h| !h.is_empty());
+        }
+    }
+    if let Some((_, rest)) = locked_by.split_once("lock was created at ") {
+        let created_at = rest.split(" (").next().unwrap_or_default().trim();
+        lock.created_at =
+            chrono::NaiveDateTime::parse_from_str(created_at, "%Y-%m-%d %H:%M:%S").ok();
+    }
+    Some(lock)
+}